@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use a bundled protoc, so building does not depend on one being installed
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_prost_build::compile_protos("proto/ledger.proto")?;
+    Ok(())
+}
@@ -1,276 +1,780 @@
+use crate::account_writer::{AccountWriter, CsvAccountWriter};
+use crate::double_entry::{Posting, TrialBalance};
+use crate::ledger_store::{LedgerStore, MemoryStore, StoreError};
+use crate::snapshot::Snapshot;
+use crate::wal::{InputPosition, ResumePoint, Wal};
 use crate::ClientAccount;
+use crate::DisputeState;
+use crate::LedgerError;
+use crate::LedgerEvent;
 use crate::Transaction;
 use crate::TxType;
 use rust_decimal::Decimal;
-use std::collections::hash_map::Entry::Occupied;
-use std::collections::hash_map::Entry::Vacant;
-use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
 
-pub struct AccountManager {
-    pub accounts: HashMap<u16, ClientAccount>,
-    transactions: HashMap<u32, Transaction>,
+/// How many events a subscriber may fall behind before missing some.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DisputePolicy {
+    #[default]
+    DepositsOnly,
+    DepositsAndWithdrawals,
 }
 
-impl std::fmt::Display for AccountManager {
-    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
-        self.to_csv().unwrap();
-        Ok(())
+/// What became of an accepted transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxOutcome {
+    Applied,
+    /// An identical transaction was already applied, so nothing changed.
+    Replayed,
+}
+
+impl fmt::Display for TxOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxOutcome::Applied => f.write_str("applied"),
+            TxOutcome::Replayed => f.write_str("replayed"),
+        }
     }
 }
 
+pub struct AccountManager {
+    store: Box<dyn LedgerStore>,
+    dispute_policy: DisputePolicy,
+    wal: Option<Arc<Mutex<Wal>>>,
+    resume: ResumePoint,
+    /// The last write-ahead log entry reflected in the store.
+    wal_seq: u64,
+    /// Where the transaction being processed was read from, logged along with it.
+    position: InputPosition,
+    subscribers: Option<broadcast::Sender<LedgerEvent>>,
+}
+
 impl Default for AccountManager {
     fn default() -> Self {
-        AccountManager {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-        }
+        AccountManager::new(Box::<MemoryStore>::default(), DisputePolicy::default())
+    }
+}
+
+impl std::fmt::Display for AccountManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = Vec::new();
+        self.write_accounts(&CsvAccountWriter, &mut buf)
+            .map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&buf))
     }
 }
 
 impl AccountManager {
-    fn to_csv(&self) -> Result<(), Box<dyn Error>> {
-        let mut wtr = csv::Writer::from_writer(io::stdout());
-        for acc in self.accounts.values() {
-            wtr.serialize(acc).unwrap();
+    pub fn new(store: Box<dyn LedgerStore>, dispute_policy: DisputePolicy) -> Self {
+        AccountManager {
+            store,
+            dispute_policy,
+            wal: None,
+            resume: ResumePoint::default(),
+            wal_seq: 0,
+            position: InputPosition::default(),
+            subscribers: None,
+        }
+    }
+
+    /// Rebuilds state by replaying the write-ahead log at `path`, then logs every
+    /// transaction accepted from now on before it takes effect. Entries covered by a snapshot
+    /// the manager was restored from are skipped as they are read. The log holds the history
+    /// since the last snapshot, so it is meant for a store which starts from that snapshot or
+    /// empty, such as the `MemoryStore`.
+    pub fn with_wal<P: AsRef<Path>>(mut self, path: P) -> Result<Self, Box<dyn Error>> {
+        let snapshot_seq = self.wal_seq;
+        let wal = Wal::open(path, |entry| {
+            if entry.seq <= snapshot_seq {
+                return Ok(());
+            }
+            match (self.process_tx(&entry.tx), entry.rejected) {
+                (Ok(_), false) | (Err(_), true) => {}
+                (Err(e), false) => {
+                    return Err(format!(
+                        "Write-ahead log entry {} could not be replayed: {}",
+                        entry.seq, e
+                    )
+                    .into())
+                }
+                (Ok(_), true) => {
+                    return Err(format!(
+                        "Write-ahead log entry {} was rejected but is accepted on replay",
+                        entry.seq
+                    )
+                    .into())
+                }
+            }
+            self.resume.record(&entry.position, entry.tx.client);
+            self.wal_seq = entry.seq;
+            Ok(())
+        })?;
+        self.wal = Some(Arc::new(Mutex::new(wal)));
+        Ok(self)
+    }
+
+    /// Restores the accounts, transactions & events of a snapshot into a `MemoryStore`.
+    /// A write-ahead log added afterwards only replays the entries after the snapshot.
+    pub fn from_snapshot(
+        snapshot: Snapshot,
+        dispute_policy: DisputePolicy,
+    ) -> Result<Self, StoreError> {
+        let (wal_seq, resume, accounts, transactions, events) = snapshot.into_parts();
+        let mut store = MemoryStore::default();
+        for account in accounts {
+            store.put_account(account)?;
+        }
+        for tx in transactions {
+            store.put_transaction(tx)?;
+        }
+        for event in events {
+            store.append_event(event)?;
+        }
+        Ok(AccountManager {
+            resume,
+            wal_seq,
+            ..AccountManager::new(Box::new(store), dispute_policy)
+        })
+    }
+
+    /// Writes a snapshot to `path`, then drops the write-ahead log entries it covers.
+    pub fn write_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let snapshot = self.snapshot().map_err(|e| e as Box<dyn Error>)?;
+        snapshot.write(path)?;
+        match &self.wal {
+            Some(wal) => wal.lock().unwrap().compact(snapshot.wal_seq()),
+            None => Ok(()),
+        }
+    }
+
+    /// The write-ahead log shared by this manager & any shards split from it.
+    pub(crate) fn wal(&self) -> Option<Arc<Mutex<Wal>>> {
+        self.wal.clone()
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot, StoreError> {
+        let transactions = self.store.transactions().collect::<Result<_, _>>()?;
+        let events = self.store.events().collect::<Result<_, _>>()?;
+        Ok(Snapshot::new(
+            self.wal_seq,
+            self.resume.clone(),
+            self.store.accounts()?,
+            transactions,
+            events,
+        ))
+    }
+
+    /// The input rows already applied by an earlier run, which can be skipped.
+    pub fn resume_point(&self) -> &ResumePoint {
+        &self.resume
+    }
+
+    pub fn with_dispute_policy(dispute_policy: DisputePolicy) -> Self {
+        AccountManager::new(Box::<MemoryStore>::default(), dispute_policy)
+    }
+
+    /// Partitions accounts by `client % shards`, the transactions being shared by every shard.
+    pub fn split(self, shards: usize) -> Vec<AccountManager> {
+        let AccountManager {
+            store,
+            dispute_policy,
+            wal,
+            resume,
+            wal_seq,
+            subscribers,
+            ..
+        } = self;
+        store
+            .split(shards)
+            .into_iter()
+            .map(|store| AccountManager {
+                wal: wal.clone(),
+                resume: resume.clone(),
+                wal_seq,
+                subscribers: subscribers.clone(),
+                ..AccountManager::new(store, dispute_policy)
+            })
+            .collect()
+    }
+
+    pub fn merge(shards: Vec<AccountManager>) -> Result<AccountManager, StoreError> {
+        let mut shards = shards.into_iter();
+        let first = match shards.next() {
+            Some(first) => first,
+            None => return Ok(AccountManager::default()),
+        };
+        let mut resume = first.resume;
+        let mut wal_seq = first.wal_seq;
+        let mut stores = Vec::new();
+        for shard in shards {
+            resume.merge(shard.resume);
+            wal_seq = wal_seq.max(shard.wal_seq);
+            stores.push(shard.store);
+        }
+        Ok(AccountManager {
+            wal: first.wal,
+            resume,
+            wal_seq,
+            subscribers: first.subscribers,
+            ..AccountManager::new(first.store.merge(stores)?, first.dispute_policy)
+        })
+    }
+
+    pub fn account(&self, client: u16) -> Result<Option<ClientAccount>, StoreError> {
+        self.store.account(client)
+    }
+
+    /// An applied deposit or withdrawal.
+    pub fn transaction(&self, tx: u32) -> Result<Option<Transaction>, StoreError> {
+        Ok(self
+            .store
+            .transaction(tx)?
+            .filter(|t| t.rejection.is_none()))
+    }
+
+    /// Every account, in client id order.
+    pub fn accounts(&self) -> Result<Vec<ClientAccount>, StoreError> {
+        self.store.accounts()
+    }
+
+    /// The events which changed a client's account, oldest first. Folding them with
+    /// `ClientAccount::from_events` gives the account's current state.
+    pub fn events_for_client(&self, client: u16) -> Result<Vec<LedgerEvent>, StoreError> {
+        self.store.events_for_client(client)
+    }
+
+    /// Posts every event to the general ledger, as balanced debit & credit entries between
+    /// client & system accounts.
+    pub fn trial_balance(&self) -> Result<TrialBalance, StoreError> {
+        let mut trial_balance = TrialBalance::default();
+        for event in self.store.events() {
+            trial_balance.extend(&Posting::for_event(&event?));
         }
-        wtr.flush()?;
-        Ok(())
+        Ok(trial_balance)
+    }
+
+    /// Every event, grouped by client in client id order.
+    pub fn events(&self) -> Result<Vec<LedgerEvent>, StoreError> {
+        self.store.events().collect()
     }
-    fn process_deposit(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
+
+    /// Receives every event recorded from now on.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<LedgerEvent> {
+        self.subscribers
+            .get_or_insert_with(|| broadcast::channel(SUBSCRIBER_BUFFER).0)
+            .subscribe()
+    }
+
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap().sync()?;
+        }
+        self.store.flush()
+    }
+
+    pub fn write_accounts(
+        &self,
+        writer: &dyn AccountWriter,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let accounts = self.store.accounts().map_err(|e| e as Box<dyn Error>)?;
+        writer.write_accounts(&mut accounts.iter(), out)
+    }
+
+    fn process_deposit(&mut self, tx: &Transaction) -> Result<TxOutcome, LedgerError> {
         let amount = match tx.amount {
             Some(a) => {
                 if a.lt(&Decimal::new(0, 0)) {
-                    return Err("Cannot Deposit a Negative Amount".into());
+                    return Err(LedgerError::NegativeAmount {
+                        client: tx.client,
+                        tx: tx.tx,
+                        amount: a,
+                    });
                 } else {
                     a
                 }
             }
-            None => return Err("Amount Required".into()),
-        };
-        match self.transactions.entry(tx.tx) {
-            Occupied(_) => return Err("Duplicate Transaction".into()),
-            Vacant(e) => {
-                e.insert(tx.clone());
+            None => {
+                return Err(LedgerError::AmountRequired {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
+        };
+        if let Some(outcome) = self.check_retry(tx)? {
+            return Ok(outcome);
         }
-        match self.accounts.entry(tx.client) {
-            Occupied(mut e) => {
-                let account = e.get_mut();
-                if account.locked {
-                    return Err("Account Locked due to Chargeback".into());
+        let account = match self.store.account(tx.client).map_err(storage(tx))? {
+            Some(account) => {
+                if account.locked() {
+                    return self.reject(
+                        tx,
+                        LedgerError::AccountLocked {
+                            client: tx.client,
+                            tx: tx.tx,
+                        },
+                    );
                 }
-                account.available += amount;
-                account.total = account.available - account.held;
+                account
             }
-            Vacant(e) => {
-                let new_account = ClientAccount {
-                    available: amount,
-                    client: tx.client,
-                    held: Decimal::new(0, 0),
-                    locked: false,
-                    total: amount,
-                };
-                e.insert(new_account);
-            }
-        }
-        Ok(())
+            None => ClientAccount::new(tx.client),
+        };
+        let deposited = LedgerEvent::Deposited {
+            client: tx.client,
+            tx: tx.tx,
+            amount,
+            currency: tx.currency,
+        };
+        self.save(tx, account, tx.clone(), vec![deposited])
     }
 
-    fn process_withdraw(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
+    fn process_withdraw(&mut self, tx: &Transaction) -> Result<TxOutcome, LedgerError> {
         let amount = match tx.amount {
             Some(a) => {
                 if a.lt(&Decimal::new(0, 0)) {
-                    return Err("Cannot Withdraw a Negative Amount".into());
+                    return Err(LedgerError::NegativeAmount {
+                        client: tx.client,
+                        tx: tx.tx,
+                        amount: a,
+                    });
                 } else {
                     a
                 }
             }
-            None => return Err("Amount Required".into()),
-        };
-        match self.transactions.entry(tx.tx) {
-            Occupied(_) => return Err("Duplicate Transaction".into()),
-            Vacant(e) => {
-                e.insert(tx.clone());
+            None => {
+                return Err(LedgerError::AmountRequired {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
+        };
+        if let Some(outcome) = self.check_retry(tx)? {
+            return Ok(outcome);
         }
-        match self.accounts.entry(tx.client) {
-            Occupied(mut e) => {
-                let account = e.get_mut();
-                if account.locked {
-                    return Err("Account Locked due to Chargeback".into());
+        let account = match self.store.account(tx.client).map_err(storage(tx))? {
+            Some(account) => {
+                if account.locked() {
+                    return self.reject(
+                        tx,
+                        LedgerError::AccountLocked {
+                            client: tx.client,
+                            tx: tx.tx,
+                        },
+                    );
                 }
-                if (account.available - amount).lt(&Decimal::new(0, 0)) {
-                    return Err("Insufficient Funds".into());
+                let available = account.balance(tx.currency).available();
+                if (available - amount).lt(&Decimal::new(0, 0)) {
+                    return self.reject(
+                        tx,
+                        LedgerError::InsufficientFunds {
+                            client: tx.client,
+                            tx: tx.tx,
+                            available,
+                            requested: amount,
+                        },
+                    );
                 }
-                account.available -= amount;
-                account.total = account.available - account.held;
+                account
             }
-            Vacant(_) => return Err("Cannot withdraw from a non existent account".into()),
+            None => {
+                return self.reject(
+                    tx,
+                    LedgerError::AccountNotFound {
+                        client: tx.client,
+                        tx: tx.tx,
+                    },
+                )
+            }
+        };
+        let withdrew = LedgerEvent::Withdrew {
+            client: tx.client,
+            tx: tx.tx,
+            amount,
+            currency: tx.currency,
+        };
+        self.save(tx, account, tx.clone(), vec![withdrew])
+    }
+
+    /// A deposit or withdrawal reusing the id of a stored one is a retry when it is otherwise
+    /// identical, which is answered as the original was, & a conflict when it is not. A
+    /// retry of one rejected because of the ledger's state is rejected the same way, even
+    /// once the state has changed, so resubmitting never changes an answer already given.
+    /// The id of a rejected transaction is not taken though, so a different one may use it.
+    fn check_retry(&self, tx: &Transaction) -> Result<Option<TxOutcome>, LedgerError> {
+        let stored = match self.store.transaction(tx.tx).map_err(storage(tx))? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let identical = stored.tx_type == tx.tx_type
+            && stored.client == tx.client
+            && stored.amount == tx.amount
+            && stored.currency == tx.currency;
+        match (identical, stored.rejection) {
+            (true, Some(rejection)) => Err(rejection),
+            (true, None) => Ok(Some(TxOutcome::Replayed)),
+            (false, Some(_)) => Ok(None),
+            (false, None) => Err(LedgerError::TransactionConflict {
+                client: tx.client,
+                tx: tx.tx,
+            }),
         }
-        Ok(())
     }
 
-    fn process_dispute(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
-        let mut _account = match self.accounts.entry(tx.client) {
-            Occupied(entry) => {
-                if entry.get().locked {
-                    return Err("Account Locked due to Chargeback".into());
-                }
-                entry
-            }
-            Vacant(_) => {
-                return Err("No Associated Client Account Found".into());
-            }
+    /// A dispute, resolve or chargeback reusing an action id from the client's journal is a
+    /// retry when it is the same action on the same transaction, which changes nothing, & a
+    /// conflict when it is not. Transactions without an action id are never retries.
+    fn check_action(&self, tx: &Transaction) -> Result<Option<TxOutcome>, LedgerError> {
+        let action = match tx.action {
+            Some(action) => action,
+            None => return Ok(None),
         };
-        match self.transactions.entry(tx.tx) {
-            Occupied(mut e) => {
-                let disputed_tx = e.get_mut();
-                let account = _account.get_mut();
-                if disputed_tx.tx_type.as_ref().unwrap() != &TxType::Deposit {
-                    return Err("Only a Deposit can be disputed".into());
-                }
-                let amount = match disputed_tx.amount {
-                    Some(a) => a,
-                    None => return Err("Amount Required".into()),
-                };
-                account.available -= amount;
-                account.held += amount;
-                disputed_tx.is_disputed = true;
+        match self.store.action(tx.client, action).map_err(storage(tx))? {
+            None => Ok(None),
+            Some((id, tx_type)) if id == tx.tx && Some(&tx_type) == tx.tx_type.as_ref() => {
+                Ok(Some(TxOutcome::Replayed))
             }
-            Vacant(_) => {
-                return Err("No Associated Transaction to-be-disputed could be Found".into());
-            }
-        };
-        Ok(())
+            Some(_) => Err(LedgerError::ActionConflict {
+                client: tx.client,
+                tx: tx.tx,
+                action,
+            }),
+        }
     }
 
-    fn process_resolve(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
-        let mut _account = match self.accounts.entry(tx.client) {
-            Occupied(entry) => {
-                if entry.get().locked {
-                    return Err("Account Locked due to Chargeback".into());
-                }
-                entry
+    /// Stores a deposit or withdrawal rejected because of the ledger's state, such as a lack
+    /// of funds, along with its rejection, which `check_retry` answers its retries with.
+    /// Rejections which only depend on the transaction itself are not stored.
+    fn reject(
+        &mut self,
+        tx: &Transaction,
+        rejection: LedgerError,
+    ) -> Result<TxOutcome, LedgerError> {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().unwrap();
+            self.wal_seq = wal
+                .append_rejected(&self.position, tx)
+                .map_err(|e| storage(tx)(e.into()))?;
+        }
+        self.resume.record(&self.position, tx.client);
+        let mut stored_tx = tx.clone();
+        stored_tx.rejection = Some(rejection.clone());
+        self.store.put_transaction(stored_tx).map_err(storage(tx))?;
+        Err(rejection)
+    }
+
+    /// Applies the events of `tx` to the account, then writes back the account & transaction
+    /// & appends the events to the journal in one write.
+    fn save(
+        &mut self,
+        tx: &Transaction,
+        mut account: ClientAccount,
+        stored_tx: Transaction,
+        events: Vec<LedgerEvent>,
+    ) -> Result<TxOutcome, LedgerError> {
+        for event in &events {
+            account.apply(event);
+        }
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().unwrap();
+            self.wal_seq = wal
+                .append(&self.position, tx)
+                .map_err(|e| storage(tx)(e.into()))?;
+        }
+        self.resume.record(&self.position, tx.client);
+        self.store
+            .write_changes(account, stored_tx, &events)
+            .map_err(storage(tx))?;
+        if let Some(subscribers) = &self.subscribers {
+            for event in events {
+                // Sending only fails when nobody is subscribed
+                let _ = subscribers.send(event);
             }
-            Vacant(_) => {
-                return Err("No Associated Client Account Found".into());
+        }
+        Ok(TxOutcome::Applied)
+    }
+
+    /// Looks up the account of the client disputing, resolving or charging back along with
+    /// the transaction it references, which must belong to that client. A currency given
+    /// with a dispute, resolve or chargeback must be that of the referenced transaction.
+    fn referenced_tx(&self, tx: &Transaction) -> Result<(Transaction, ClientAccount), LedgerError> {
+        let account = match self.store.account(tx.client).map_err(storage(tx))? {
+            Some(a) => a,
+            None => {
+                return Err(LedgerError::AccountNotFound {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
         };
-        match self.transactions.entry(tx.tx) {
-            Occupied(mut e) => {
-                let disputed_tx = e.get_mut();
-                if !disputed_tx.is_disputed {
-                    return Err("Transaction is not disputed".into());
-                }
-                let account = _account.get_mut();
-                let amount = match disputed_tx.amount {
-                    Some(a) => a,
-                    None => return Err("Amount Required".into()),
-                };
-                account.available += amount;
-                account.held -= amount;
-                disputed_tx.is_disputed = false;
-            }
-            Vacant(_) => {
-                return Err("No Associated Transaction to-be-resolved could be Found".into());
+        if account.locked() {
+            return Err(LedgerError::AccountLocked {
+                client: tx.client,
+                tx: tx.tx,
+            });
+        }
+        let referenced_tx = match self.transaction(tx.tx).map_err(storage(tx))? {
+            Some(t) => t,
+            None => {
+                return Err(LedgerError::TransactionNotFound {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
         };
-        Ok(())
+        check_owner(&referenced_tx, tx)?;
+        check_currency(&referenced_tx, tx)?;
+        Ok((referenced_tx, account))
     }
 
-    fn process_chargeback(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
-        let mut _account = match self.accounts.entry(tx.client) {
-            Occupied(entry) => {
-                if entry.get().locked {
-                    return Err("Account Locked due to Chargeback".into());
-                }
-                entry
-            }
-            Vacant(_) => {
-                return Err("No Associated Client Account Found".into());
+    fn process_dispute(&mut self, tx: &Transaction) -> Result<TxOutcome, LedgerError> {
+        if let Some(outcome) = self.check_action(tx)? {
+            return Ok(outcome);
+        }
+        let (mut disputed_tx, account) = self.referenced_tx(tx)?;
+        check_transition(&disputed_tx, tx, DisputeState::Disputed)?;
+        let disputed_type = disputed_tx.tx_type.clone().unwrap();
+        let disputable = match disputed_type {
+            TxType::Deposit => true,
+            TxType::Withdraw => self.dispute_policy == DisputePolicy::DepositsAndWithdrawals,
+            _ => false,
+        };
+        if !disputable {
+            return Err(LedgerError::NotDisputable {
+                client: tx.client,
+                tx: tx.tx,
+                tx_type: disputed_type,
+            });
+        }
+        let amount = match disputed_tx.amount {
+            Some(a) => a,
+            None => {
+                return Err(LedgerError::AmountRequired {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
         };
-        match self.transactions.entry(tx.tx) {
-            Occupied(mut e) => {
-                let disputed_tx = e.get_mut();
-                if !disputed_tx.is_disputed {
-                    return Err("Transaction is not disputed".into());
-                }
-                let account = _account.get_mut();
-                let amount = match disputed_tx.amount {
-                    Some(a) => a,
-                    None => return Err("Amount Required".into()),
-                };
-                account.held -= amount;
-                account.total = account.available - account.held;
-                account.locked = true;
+        // A disputed withdrawal has its funds provisionally credited back as held
+        let held = LedgerEvent::FundsHeld {
+            client: tx.client,
+            tx: tx.tx,
+            amount,
+            tx_type: disputed_type,
+            currency: disputed_tx.currency,
+            action: tx.action,
+        };
+        disputed_tx.dispute_state = DisputeState::Disputed;
+        self.save(tx, account, disputed_tx, vec![held])
+    }
+
+    fn process_resolve(&mut self, tx: &Transaction) -> Result<TxOutcome, LedgerError> {
+        if let Some(outcome) = self.check_action(tx)? {
+            return Ok(outcome);
+        }
+        let (mut disputed_tx, account) = self.referenced_tx(tx)?;
+        check_transition(&disputed_tx, tx, DisputeState::Resolved)?;
+        let amount = match disputed_tx.amount {
+            Some(a) => a,
+            None => {
+                return Err(LedgerError::AmountRequired {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
-            Vacant(_) => {
-                return Err("No Associated Transaction to-be-chargedback could be Found".into());
+        };
+        // When a withdrawal stands, its provisional credit is dropped
+        let released = LedgerEvent::FundsReleased {
+            client: tx.client,
+            tx: tx.tx,
+            amount,
+            tx_type: disputed_tx.tx_type.clone().unwrap(),
+            currency: disputed_tx.currency,
+            action: tx.action,
+        };
+        disputed_tx.dispute_state = DisputeState::Resolved;
+        self.save(tx, account, disputed_tx, vec![released])
+    }
+
+    fn process_chargeback(&mut self, tx: &Transaction) -> Result<TxOutcome, LedgerError> {
+        if let Some(outcome) = self.check_action(tx)? {
+            return Ok(outcome);
+        }
+        let (mut disputed_tx, account) = self.referenced_tx(tx)?;
+        check_transition(&disputed_tx, tx, DisputeState::ChargedBack)?;
+        let amount = match disputed_tx.amount {
+            Some(a) => a,
+            None => {
+                return Err(LedgerError::AmountRequired {
+                    client: tx.client,
+                    tx: tx.tx,
+                })
             }
         };
-        Ok(())
+        // A reversed withdrawal has its funds returned to the client
+        let events = vec![
+            LedgerEvent::ChargedBack {
+                client: tx.client,
+                tx: tx.tx,
+                amount,
+                tx_type: disputed_tx.tx_type.clone().unwrap(),
+                currency: disputed_tx.currency,
+                action: tx.action,
+            },
+            LedgerEvent::AccountLocked {
+                client: tx.client,
+                tx: tx.tx,
+                action: tx.action,
+            },
+        ];
+        disputed_tx.dispute_state = DisputeState::ChargedBack;
+        self.save(tx, account, disputed_tx, events)
+    }
+
+    /// Processes a transaction read from `position`, which is logged with it.
+    pub fn process_tx_at(
+        &mut self,
+        tx: &Transaction,
+        position: InputPosition,
+    ) -> Result<TxOutcome, LedgerError> {
+        self.position = position;
+        let result = self.process_tx(tx);
+        self.position = InputPosition::default();
+        result
     }
 
-    pub fn process_tx(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
-        match &tx.tx_type {
+    /// Applies a transaction, unless it repeats a deposit, withdrawal or action already applied.
+    pub fn process_tx(&mut self, tx: &Transaction) -> Result<TxOutcome, LedgerError> {
+        let result = match &tx.tx_type {
             Some(t) => match t {
-                TxType::Deposit => self.process_deposit(tx)?,
-                TxType::Withdraw => self.process_withdraw(tx)?,
-                TxType::Dispute => self.process_dispute(tx)?,
-                TxType::Resolve => self.process_resolve(tx)?,
-                TxType::Chargeback => self.process_chargeback(tx)?,
+                TxType::Deposit => self.process_deposit(tx),
+                TxType::Withdraw => self.process_withdraw(tx),
+                TxType::Dispute => self.process_dispute(tx),
+                TxType::Resolve => self.process_resolve(tx),
+                TxType::Chargeback => self.process_chargeback(tx),
             },
-            None => return Err("No Tx Type provided".into()),
+            None => Err(LedgerError::MissingTxType {
+                client: tx.client,
+                tx: tx.tx,
+            }),
         };
-        Ok(())
+        debug_assert!(
+            self.store
+                .account(tx.client)
+                .ok()
+                .flatten()
+                .is_none_or(|a| a.is_balanced()),
+            "Account {} out of balance after tx {}",
+            tx.client,
+            tx.tx
+        );
+        result
     }
 }
 
+pub fn shard_of(client: u16, shards: usize) -> usize {
+    client as usize % shards
+}
+
+/// Runs `f` with a ledger shared by the servers locked, on a blocking thread. The store &
+/// write-ahead log do file I/O, which must not hold up the async runtime's workers.
+pub async fn with_ledger<T, F>(acc_man: &Arc<Mutex<AccountManager>>, f: F) -> T
+where
+    F: FnOnce(&mut AccountManager) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let acc_man = Arc::clone(acc_man);
+    match tokio::task::spawn_blocking(move || f(&mut acc_man.lock().unwrap())).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+fn storage(tx: &Transaction) -> impl Fn(StoreError) -> LedgerError + '_ {
+    move |e| LedgerError::Storage {
+        client: tx.client,
+        tx: tx.tx,
+        error: e.to_string(),
+    }
+}
+
+fn check_owner(disputed_tx: &Transaction, tx: &Transaction) -> Result<(), LedgerError> {
+    if disputed_tx.client != tx.client {
+        return Err(LedgerError::ClientMismatch {
+            client: tx.client,
+            tx: tx.tx,
+            owner: disputed_tx.client,
+        });
+    }
+    Ok(())
+}
+
+fn check_currency(referenced_tx: &Transaction, tx: &Transaction) -> Result<(), LedgerError> {
+    match tx.currency {
+        Some(found) if referenced_tx.currency != Some(found) => {
+            Err(LedgerError::CurrencyMismatch {
+                client: tx.client,
+                tx: tx.tx,
+                expected: referenced_tx.currency,
+                found,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_transition(
+    disputed_tx: &Transaction,
+    tx: &Transaction,
+    next: DisputeState,
+) -> Result<(), LedgerError> {
+    if !disputed_tx.dispute_state.can_transition_to(next) {
+        return Err(LedgerError::InvalidDisputeTransition {
+            client: tx.client,
+            tx: tx.tx,
+            from: disputed_tx.dispute_state,
+            to: next,
+        });
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Currency;
 
     #[test]
     fn deposit_new_account() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(1, 0)));
         let result = acc_man.process_tx(&tx);
         assert!(result.is_ok());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(1, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(1, 0));
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(1, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(1, 0));
     }
 
     #[test]
     fn deposit_negative_amount_account() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(-1, 0)),
-            is_disputed: false,
-        };
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(-1, 0)));
         let result = acc_man.process_tx(&tx);
         assert!(result.is_err());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_none());
     }
 
@@ -278,239 +782,206 @@ mod tests {
     fn withdraw_negative_amount_account() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(-1, 0)),
-            is_disputed: false,
-        };
+        let tx = Transaction::new(TxType::Withdraw, client_id, 1u32, Some(Decimal::new(-1, 0)));
         let result = acc_man.process_tx(&tx);
         assert!(result.is_err());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_none());
     }
 
     #[test]
-    fn deposit_duplicate_tx() {
+    fn withdraw_retried_after_rejection_keeps_its_rejection() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(1, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Deposit),
+        let tx2 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(3, 0)));
+        let rejection = LedgerError::InsufficientFunds {
             client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
+            tx: 2u32,
+            available: Decimal::new(1, 0),
+            requested: Decimal::new(3, 0),
         };
-        assert!(acc_man.process_tx(&tx2).is_err());
+        assert_eq!(acc_man.process_tx(&tx2), Err(rejection.clone()));
+        let tx3 = Transaction::new(TxType::Deposit, client_id, 3u32, Some(Decimal::new(5, 0)));
+        assert!(acc_man.process_tx(&tx3).is_ok());
+
+        // The funds are there now, but the retry is answered as the original was
+        assert_eq!(acc_man.process_tx(&tx2), Err(rejection));
+        assert!(acc_man.transaction(2u32).unwrap().is_none());
+        let dispute = Transaction::new(TxType::Dispute, client_id, 2u32, None);
+        assert_eq!(
+            acc_man.process_tx(&dispute),
+            Err(LedgerError::TransactionNotFound {
+                client: client_id,
+                tx: 2u32,
+            })
+        );
+
+        // A different transaction may still use the id
+        let tx4 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(2, 0)));
+        assert_eq!(acc_man.process_tx(&tx4), Ok(TxOutcome::Applied));
+        assert_eq!(
+            acc_man.account(client_id).unwrap().unwrap().available(),
+            Decimal::new(4, 0)
+        );
+    }
+
+    #[test]
+    fn deposit_retried_tx() {
+        let mut acc_man = AccountManager::default();
+        let client_id = 1u16;
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(1, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(1, 0)));
+        assert_eq!(acc_man.process_tx(&tx2), Ok(TxOutcome::Replayed));
+        let tx3 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(2, 0)));
+        assert_eq!(
+            acc_man.process_tx(&tx3),
+            Err(LedgerError::TransactionConflict {
+                client: client_id,
+                tx: 1u32,
+            })
+        );
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(1, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(1, 0));
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(1, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(1, 0));
     }
 
     #[test]
     fn deposit_multiple_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(1, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 2u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx2 = Transaction::new(TxType::Deposit, client_id, 2u32, Some(Decimal::new(1, 0)));
         assert!(acc_man.process_tx(&tx2).is_ok());
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(2, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(2, 0));
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(2, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(2, 0));
     }
 
     #[test]
     fn withdraw_new_account() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx = Transaction::new(TxType::Withdraw, client_id, 1u32, Some(Decimal::new(1, 0)));
         let result = acc_man.process_tx(&tx);
         assert!(result.is_err());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_none());
     }
 
     #[test]
-    fn withdraw_duplicate_tx() {
+    fn withdraw_retried_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx).is_ok());
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 2u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(1, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 2u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
-        assert!(acc_man.process_tx(&tx2).is_err());
+        let tx2 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(1, 0)));
+        assert_eq!(acc_man.process_tx(&tx2), Ok(TxOutcome::Replayed));
+        // The id of the deposit reused by a withdrawal
+        let tx3 = Transaction::new(TxType::Withdraw, client_id, 1u32, Some(Decimal::new(9, 0)));
+        assert_eq!(
+            acc_man.process_tx(&tx3),
+            Err(LedgerError::TransactionConflict {
+                client: client_id,
+                tx: 1u32,
+            })
+        );
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(8, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(8, 0));
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(8, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(8, 0));
     }
 
     #[test]
     fn withdraw_multiple_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(10, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(10, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 2u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx2 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(1, 0)));
         assert!(acc_man.process_tx(&tx2).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 3u32,
-            amount: Some(Decimal::new(1, 0)),
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Withdraw, client_id, 3u32, Some(Decimal::new(1, 0)));
         assert!(acc_man.process_tx(&tx3).is_ok());
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(8, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(8, 0));
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(8, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(8, 0));
     }
 
     #[test]
     fn withdraw_insufficient_funds_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(10, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(10, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 2u32,
-            amount: Some(Decimal::new(11, 0)),
-            is_disputed: false,
-        };
-        assert!(acc_man.process_tx(&tx2).is_err());
+        let tx2 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(11, 0)));
+        assert_eq!(
+            acc_man.process_tx(&tx2),
+            Err(LedgerError::InsufficientFunds {
+                client: client_id,
+                tx: 2u32,
+                available: Decimal::new(10, 0),
+                requested: Decimal::new(11, 0),
+            })
+        );
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(10, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(10, 0));
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(10, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(10, 0));
     }
 
     #[test]
     fn dispute_a_deposit_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(5, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(5, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Dispute),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx2).is_ok());
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(0, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(5, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(5, 0));
-        match acc_man.transactions.entry(1u32) {
-            Occupied(e) => assert_eq!(e.get().is_disputed, true),
-            Vacant(_e) => assert!(false),
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(0, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(5, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(5, 0));
+        match acc_man.store.transaction(1u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::Disputed),
+            None => panic!("Transaction not found"),
         };
     }
 
@@ -518,42 +989,110 @@ mod tests {
     fn dispute_a_withdraw_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(10, 0)),
-            is_disputed: false,
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(10, 0)));
+        assert!(acc_man.process_tx(&tx).is_ok());
+        let tx1 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 2u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx2),
+            Err(LedgerError::NotDisputable {
+                client: client_id,
+                tx: 2u32,
+                tx_type: TxType::Withdraw,
+            })
+        );
+
+        let maybe_account = acc_man.account(client_id).unwrap();
+        assert!(maybe_account.is_some());
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(1, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(1, 0));
+        match acc_man.store.transaction(2u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::None),
+            None => panic!("Transaction not found"),
         };
+    }
+
+    #[test]
+    fn dispute_a_withdraw_tx_when_policy_allows() {
+        let mut acc_man =
+            AccountManager::with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        let client_id = 1u16;
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(10, 0)));
         assert!(acc_man.process_tx(&tx).is_ok());
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Withdraw),
-            client: client_id,
-            tx: 2u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
+        let tx1 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 2u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let maybe_account = acc_man.account(client_id).unwrap();
+        assert!(maybe_account.is_some());
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(1, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(9, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(10, 0));
+        match acc_man.store.transaction(2u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::Disputed),
+            None => panic!("Transaction not found"),
         };
+    }
+
+    #[test]
+    fn resolve_a_disputed_withdraw_tx() {
+        let mut acc_man =
+            AccountManager::with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        let client_id = 1u16;
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(10, 0)));
+        assert!(acc_man.process_tx(&tx).is_ok());
+        let tx1 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Dispute),
-            client: client_id,
-            tx: 2u32,
-            amount: None,
-            is_disputed: false,
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 2u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 2u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let maybe_account = acc_man.account(client_id).unwrap();
+        assert!(maybe_account.is_some());
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(1, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(1, 0));
+        match acc_man.store.transaction(2u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::Resolved),
+            None => panic!("Transaction not found"),
         };
-        assert!(acc_man.process_tx(&tx2).is_err());
+    }
 
-        let maybe_account = acc_man.accounts.get(&client_id);
+    #[test]
+    fn chargeback_a_disputed_withdraw_tx() {
+        let mut acc_man =
+            AccountManager::with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        let client_id = 1u16;
+        let tx = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(10, 0)));
+        assert!(acc_man.process_tx(&tx).is_ok());
+        let tx1 = Transaction::new(TxType::Withdraw, client_id, 2u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 2u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Chargeback, client_id, 2u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(1, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(1, 0));
-        match acc_man.transactions.entry(1u32) {
-            Occupied(e) => assert_eq!(e.get().is_disputed, false),
-            Vacant(_e) => assert!(false),
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(10, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(account.locked());
+        assert_eq!(account.total(), Decimal::new(10, 0));
+        match acc_man.store.transaction(2u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::ChargedBack),
+            None => panic!("Transaction not found"),
         };
     }
 
@@ -561,63 +1100,140 @@ mod tests {
     fn resolve_a_dispute_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Dispute),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx2).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Resolve),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_ok());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(9, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, false);
-        assert_eq!(account.total, Decimal::new(9, 0));
-        match acc_man.transactions.entry(1u32) {
-            Occupied(e) => assert_eq!(e.get().is_disputed, false),
-            Vacant(_e) => assert!(false),
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(9, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(!account.locked());
+        assert_eq!(account.total(), Decimal::new(9, 0));
+        match acc_man.store.transaction(1u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::Resolved),
+            None => panic!("Transaction not found"),
         };
     }
 
     #[test]
-    fn resolve_a_non_dispute_tx() {
+    fn dispute_a_disputed_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Resolve),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx3),
+            Err(LedgerError::InvalidDisputeTransition {
+                client: client_id,
+                tx: 1u32,
+                from: DisputeState::Disputed,
+                to: DisputeState::Disputed,
+            })
+        );
+        let account: ClientAccount = acc_man.account(client_id).unwrap().unwrap();
+        assert_eq!(account.available(), Decimal::new(0, 0));
+        assert_eq!(account.held(), Decimal::new(9, 0));
+        assert_eq!(account.total(), Decimal::new(9, 0));
+    }
+
+    #[test]
+    fn resolve_a_resolved_tx() {
+        let mut acc_man = AccountManager::default();
+        let client_id = 1u16;
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let tx4 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx4),
+            Err(LedgerError::InvalidDisputeTransition {
+                client: client_id,
+                tx: 1u32,
+                from: DisputeState::Resolved,
+                to: DisputeState::Resolved,
+            })
+        );
+        let account: ClientAccount = acc_man.account(client_id).unwrap().unwrap();
+        assert_eq!(account.available(), Decimal::new(9, 0));
+        assert_eq!(account.held(), Decimal::new(0, 0));
+    }
+
+    #[test]
+    fn chargeback_a_resolved_tx() {
+        let mut acc_man = AccountManager::default();
+        let client_id = 1u16;
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let tx4 = Transaction::new(TxType::Chargeback, client_id, 1u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx4),
+            Err(LedgerError::InvalidDisputeTransition {
+                client: client_id,
+                tx: 1u32,
+                from: DisputeState::Resolved,
+                to: DisputeState::ChargedBack,
+            })
+        );
+        let account: ClientAccount = acc_man.account(client_id).unwrap().unwrap();
+        assert_eq!(account.available(), Decimal::new(9, 0));
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn dispute_a_resolved_tx() {
+        let mut acc_man = AccountManager::default();
+        let client_id = 1u16;
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let tx4 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx4).is_ok());
+        let account: ClientAccount = acc_man.account(client_id).unwrap().unwrap();
+        assert_eq!(account.available(), Decimal::new(0, 0));
+        assert_eq!(account.held(), Decimal::new(9, 0));
+        match acc_man.store.transaction(1u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::Disputed),
+            None => panic!("Transaction not found"),
         };
+    }
+
+    #[test]
+    fn charged_back_tx_is_final() {
+        for next in [
+            DisputeState::None,
+            DisputeState::Disputed,
+            DisputeState::Resolved,
+            DisputeState::ChargedBack,
+        ] {
+            assert!(!DisputeState::ChargedBack.can_transition_to(next));
+        }
+    }
+
+    #[test]
+    fn resolve_a_non_dispute_tx() {
+        let mut acc_man = AccountManager::default();
+        let client_id = 1u16;
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_err());
     }
 
@@ -625,91 +1241,194 @@ mod tests {
     fn chargeback_a_dispute_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Dispute),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx2).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Chargeback),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Chargeback, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_ok());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(0, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, true);
-        assert_eq!(account.total, Decimal::new(0, 0));
-        match acc_man.transactions.entry(1u32) {
-            Occupied(e) => assert_eq!(e.get().is_disputed, true),
-            Vacant(_e) => assert!(false),
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(0, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(account.locked());
+        assert_eq!(account.total(), Decimal::new(0, 0));
+        match acc_man.store.transaction(1u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::ChargedBack),
+            None => panic!("Transaction not found"),
         };
     }
 
+    #[test]
+    fn chargeback_is_recorded_as_events() {
+        let mut acc_man = AccountManager::default();
+        let deposit = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(9, 0)));
+        let dispute = Transaction::new(TxType::Dispute, 1, 1, None);
+        let chargeback = Transaction::new(TxType::Chargeback, 1, 1, None);
+        for tx in [&deposit, &dispute, &chargeback] {
+            assert!(acc_man.process_tx(tx).is_ok());
+        }
+        // Rejected transactions leave no events behind
+        let withdraw = Transaction::new(TxType::Withdraw, 1, 2, Some(Decimal::new(1, 0)));
+        assert!(acc_man.process_tx(&withdraw).is_err());
+        let events = acc_man.events_for_client(1).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                LedgerEvent::Deposited {
+                    client: 1,
+                    tx: 1,
+                    amount: Decimal::new(9, 0),
+                    currency: None
+                },
+                LedgerEvent::FundsHeld {
+                    client: 1,
+                    tx: 1,
+                    amount: Decimal::new(9, 0),
+                    tx_type: TxType::Deposit,
+                    currency: None,
+                    action: None
+                },
+                LedgerEvent::ChargedBack {
+                    client: 1,
+                    tx: 1,
+                    amount: Decimal::new(9, 0),
+                    tx_type: TxType::Deposit,
+                    currency: None,
+                    action: None
+                },
+                LedgerEvent::AccountLocked {
+                    client: 1,
+                    tx: 1,
+                    action: None
+                },
+            ]
+        );
+        assert_eq!(
+            ClientAccount::from_events(1, &events),
+            acc_man.account(1).unwrap().unwrap()
+        );
+        assert!(acc_man.events_for_client(2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn balances_are_kept_per_currency() {
+        let mut acc_man = AccountManager::default();
+        let eur = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0)))
+            .with_currency(Currency::EUR);
+        let gbp = Transaction::new(TxType::Deposit, 1, 2, Some(Decimal::new(4, 0)))
+            .with_currency(Currency::GBP);
+        assert!(acc_man.process_tx(&eur).is_ok());
+        assert!(acc_man.process_tx(&gbp).is_ok());
+        // Euros cannot cover a withdrawal in pounds
+        let withdraw = Transaction::new(TxType::Withdraw, 1, 3, Some(Decimal::new(5, 0)))
+            .with_currency(Currency::GBP);
+        match acc_man.process_tx(&withdraw) {
+            Err(LedgerError::InsufficientFunds { available, .. }) => {
+                assert_eq!(available, Decimal::new(4, 0))
+            }
+            other => panic!("Expected InsufficientFunds, got {:?}", other),
+        }
+        // The dispute settles in pounds, the currency of tx 2
+        let dispute = Transaction::new(TxType::Dispute, 1, 2, None);
+        assert!(acc_man.process_tx(&dispute).is_ok());
+        let account = acc_man.account(1).unwrap().unwrap();
+        assert_eq!(
+            account.balance(Some(Currency::GBP)).held(),
+            Decimal::new(4, 0)
+        );
+        assert_eq!(
+            account.balance(Some(Currency::EUR)).available(),
+            Decimal::new(10, 0)
+        );
+        assert!(account.balance(None).total().is_zero());
+    }
+
+    #[test]
+    fn dispute_in_another_currency_is_rejected() {
+        let mut acc_man = AccountManager::default();
+        let deposit = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0)))
+            .with_currency(Currency::USD);
+        assert!(acc_man.process_tx(&deposit).is_ok());
+        let dispute = Transaction::new(TxType::Dispute, 1, 1, None).with_currency(Currency::EUR);
+        assert_eq!(
+            acc_man.process_tx(&dispute),
+            Err(LedgerError::CurrencyMismatch {
+                client: 1,
+                tx: 1,
+                expected: Some(Currency::USD),
+                found: Currency::EUR,
+            })
+        );
+        let dispute = Transaction::new(TxType::Dispute, 1, 1, None).with_currency(Currency::USD);
+        assert!(acc_man.process_tx(&dispute).is_ok());
+    }
+
+    #[test]
+    fn retried_dispute_actions_are_replays() {
+        let mut acc_man = AccountManager::default();
+        let deposit = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0)));
+        let dispute = Transaction::new(TxType::Dispute, 1, 1, None).with_action(7);
+        let resolve = Transaction::new(TxType::Resolve, 1, 1, None).with_action(8);
+        for tx in [&deposit, &dispute, &resolve] {
+            assert_eq!(acc_man.process_tx(tx), Ok(TxOutcome::Applied));
+        }
+        // Without its action id the retry would open a second dispute
+        assert_eq!(acc_man.process_tx(&dispute), Ok(TxOutcome::Replayed));
+        assert_eq!(
+            acc_man.account(1).unwrap().unwrap().held(),
+            Decimal::new(0, 0)
+        );
+        let chargeback = Transaction::new(TxType::Chargeback, 1, 1, None).with_action(8);
+        assert_eq!(
+            acc_man.process_tx(&chargeback),
+            Err(LedgerError::ActionConflict {
+                client: 1,
+                tx: 1,
+                action: 8,
+            })
+        );
+
+        let actions: Vec<Option<u32>> = acc_man
+            .events_for_client(1)
+            .unwrap()
+            .iter()
+            .map(|e| e.action())
+            .collect();
+        assert_eq!(actions, vec![None, Some(7), Some(8)]);
+    }
+
     #[test]
     fn cant_deposit_to_a_locked_account() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx2 = Transaction {
-            tx_type: Some(TxType::Dispute),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx2 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx2).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Chargeback),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Chargeback, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_ok());
-        let tx4 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
-        assert!(acc_man.process_tx(&tx4).is_err());
-        let maybe_account = acc_man.accounts.get(&client_id);
+        let tx4 = Transaction::new(TxType::Deposit, client_id, 2u32, Some(Decimal::new(9, 0)));
+        assert_eq!(
+            acc_man.process_tx(&tx4),
+            Err(LedgerError::AccountLocked {
+                client: client_id,
+                tx: 2u32,
+            })
+        );
+        let maybe_account = acc_man.account(client_id).unwrap();
         assert!(maybe_account.is_some());
-        let account: &ClientAccount = maybe_account.unwrap();
-        assert_eq!(account.available, Decimal::new(0, 0));
-        assert_eq!(account.client, client_id);
-        assert_eq!(account.held, Decimal::new(0, 0));
-        assert_eq!(account.locked, true);
-        assert_eq!(account.total, Decimal::new(0, 0));
-        match acc_man.transactions.entry(1u32) {
-            Occupied(e) => assert_eq!(e.get().is_disputed, true),
-            Vacant(_e) => assert!(false),
+        let account: ClientAccount = maybe_account.unwrap();
+        assert_eq!(account.available(), Decimal::new(0, 0));
+        assert_eq!(account.client(), client_id);
+        assert_eq!(account.held(), Decimal::new(0, 0));
+        assert!(account.locked());
+        assert_eq!(account.total(), Decimal::new(0, 0));
+        match acc_man.store.transaction(1u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::ChargedBack),
+            None => panic!("Transaction not found"),
         };
     }
 
@@ -717,21 +1436,9 @@ mod tests {
     fn chargeback_a_non_dispute_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Chargeback),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Chargeback, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_err());
     }
 
@@ -739,21 +1446,9 @@ mod tests {
     fn chargeback_a_non_existent_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx1 = Transaction {
-            tx_type: Some(TxType::Deposit),
-            client: client_id,
-            tx: 1u32,
-            amount: Some(Decimal::new(9, 0)),
-            is_disputed: false,
-        };
+        let tx1 = Transaction::new(TxType::Deposit, client_id, 1u32, Some(Decimal::new(9, 0)));
         assert!(acc_man.process_tx(&tx1).is_ok());
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Chargeback),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Chargeback, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_err());
     }
 
@@ -761,13 +1456,7 @@ mod tests {
     fn chargeback_a_non_existent_customer() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Chargeback),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Chargeback, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_err());
     }
 
@@ -775,13 +1464,7 @@ mod tests {
     fn resolve_a_non_existent_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Resolve),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Resolve, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_err());
     }
 
@@ -789,13 +1472,96 @@ mod tests {
     fn dispute_a_non_existent_tx() {
         let mut acc_man = AccountManager::default();
         let client_id = 1u16;
-        let tx3 = Transaction {
-            tx_type: Some(TxType::Dispute),
-            client: client_id,
-            tx: 1u32,
-            amount: None,
-            is_disputed: false,
-        };
+        let tx3 = Transaction::new(TxType::Dispute, client_id, 1u32, None);
         assert!(acc_man.process_tx(&tx3).is_err());
     }
+
+    #[test]
+    fn dispute_another_clients_tx() {
+        let mut acc_man = AccountManager::default();
+        let owner_id = 1u16;
+        let other_id = 2u16;
+        let tx1 = Transaction::new(TxType::Deposit, owner_id, 1u32, Some(Decimal::new(5, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Deposit, other_id, 2u32, Some(Decimal::new(3, 0)));
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Dispute, other_id, 1u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx3),
+            Err(LedgerError::ClientMismatch {
+                client: other_id,
+                tx: 1u32,
+                owner: owner_id,
+            })
+        );
+
+        let owner: ClientAccount = acc_man.account(owner_id).unwrap().unwrap();
+        assert_eq!(owner.available(), Decimal::new(5, 0));
+        assert_eq!(owner.held(), Decimal::new(0, 0));
+        let other: ClientAccount = acc_man.account(other_id).unwrap().unwrap();
+        assert_eq!(other.available(), Decimal::new(3, 0));
+        assert_eq!(other.held(), Decimal::new(0, 0));
+        match acc_man.store.transaction(1u32).unwrap() {
+            Some(t) => assert_eq!(t.dispute_state, DisputeState::None),
+            None => panic!("Transaction not found"),
+        };
+    }
+
+    #[test]
+    fn resolve_another_clients_dispute() {
+        let mut acc_man = AccountManager::default();
+        let owner_id = 1u16;
+        let other_id = 2u16;
+        let tx1 = Transaction::new(TxType::Deposit, owner_id, 1u32, Some(Decimal::new(5, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Deposit, other_id, 2u32, Some(Decimal::new(3, 0)));
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Dispute, owner_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let tx4 = Transaction::new(TxType::Resolve, other_id, 1u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx4),
+            Err(LedgerError::ClientMismatch {
+                client: other_id,
+                tx: 1u32,
+                owner: owner_id,
+            })
+        );
+
+        let owner: ClientAccount = acc_man.account(owner_id).unwrap().unwrap();
+        assert_eq!(owner.available(), Decimal::new(0, 0));
+        assert_eq!(owner.held(), Decimal::new(5, 0));
+        let other: ClientAccount = acc_man.account(other_id).unwrap().unwrap();
+        assert_eq!(other.available(), Decimal::new(3, 0));
+        assert_eq!(other.held(), Decimal::new(0, 0));
+    }
+
+    #[test]
+    fn chargeback_another_clients_dispute() {
+        let mut acc_man = AccountManager::default();
+        let owner_id = 1u16;
+        let other_id = 2u16;
+        let tx1 = Transaction::new(TxType::Deposit, owner_id, 1u32, Some(Decimal::new(5, 0)));
+        assert!(acc_man.process_tx(&tx1).is_ok());
+        let tx2 = Transaction::new(TxType::Deposit, other_id, 2u32, Some(Decimal::new(3, 0)));
+        assert!(acc_man.process_tx(&tx2).is_ok());
+        let tx3 = Transaction::new(TxType::Dispute, owner_id, 1u32, None);
+        assert!(acc_man.process_tx(&tx3).is_ok());
+        let tx4 = Transaction::new(TxType::Chargeback, other_id, 1u32, None);
+        assert_eq!(
+            acc_man.process_tx(&tx4),
+            Err(LedgerError::ClientMismatch {
+                client: other_id,
+                tx: 1u32,
+                owner: owner_id,
+            })
+        );
+
+        let owner: ClientAccount = acc_man.account(owner_id).unwrap().unwrap();
+        assert_eq!(owner.held(), Decimal::new(5, 0));
+        assert!(!owner.locked());
+        let other: ClientAccount = acc_man.account(other_id).unwrap().unwrap();
+        assert_eq!(other.available(), Decimal::new(3, 0));
+        assert!(!other.locked());
+    }
 }
@@ -0,0 +1,213 @@
+use crate::ClientAccount;
+use crate::Currency;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::io::Write;
+
+/// Renders account state into any `io::Write`.
+pub trait AccountWriter {
+    fn write_accounts(
+        &self,
+        accounts: &mut dyn Iterator<Item = &ClientAccount>,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// One output row, for each currency of each client.
+#[derive(Serialize)]
+struct AccountRow {
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl AccountRow {
+    fn cells(&self, with_currency: bool) -> Vec<String> {
+        let mut cells = vec![self.client.to_string()];
+        if with_currency {
+            cells.push(self.currency.map(|c| c.to_string()).unwrap_or_default());
+        }
+        cells.extend([
+            self.available.to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked.to_string(),
+        ]);
+        cells
+    }
+}
+
+/// The rows of every account & whether a `currency` column is needed, which is only the
+/// case once a transaction came with a currency.
+fn account_rows(accounts: &mut dyn Iterator<Item = &ClientAccount>) -> (Vec<AccountRow>, bool) {
+    let rows: Vec<AccountRow> = accounts
+        .flat_map(|acc| {
+            acc.balances().iter().map(move |balance| AccountRow {
+                client: acc.client(),
+                currency: balance.currency(),
+                available: balance.available(),
+                held: balance.held(),
+                total: balance.total(),
+                locked: acc.locked(),
+            })
+        })
+        .collect();
+    let with_currency = rows.iter().any(|r| r.currency.is_some());
+    (rows, with_currency)
+}
+
+fn headers(with_currency: bool) -> Vec<&'static str> {
+    let mut headers = vec!["client"];
+    if with_currency {
+        headers.push("currency");
+    }
+    headers.extend(["available", "held", "total", "locked"]);
+    headers
+}
+
+pub struct CsvAccountWriter;
+
+impl AccountWriter for CsvAccountWriter {
+    fn write_accounts(
+        &self,
+        accounts: &mut dyn Iterator<Item = &ClientAccount>,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let (rows, with_currency) = account_rows(accounts);
+        let mut wtr = csv::Writer::from_writer(out);
+        wtr.write_record(headers(with_currency))?;
+        for row in &rows {
+            wtr.write_record(row.cells(with_currency))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+pub struct JsonAccountWriter;
+
+impl AccountWriter for JsonAccountWriter {
+    fn write_accounts(
+        &self,
+        accounts: &mut dyn Iterator<Item = &ClientAccount>,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let (rows, _) = account_rows(accounts);
+        serde_json::to_writer_pretty(&mut *out, &rows)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+pub struct TableAccountWriter;
+
+impl AccountWriter for TableAccountWriter {
+    fn write_accounts(
+        &self,
+        accounts: &mut dyn Iterator<Item = &ClientAccount>,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let (rows, with_currency) = account_rows(accounts);
+        let headers = headers(with_currency);
+        let rows: Vec<Vec<String>> = rows.iter().map(|r| r.cells(with_currency)).collect();
+        let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let header: Vec<String> = headers
+            .iter()
+            .zip(&widths)
+            .map(|(h, w)| format!(" {:>w$} ", h, w = w))
+            .collect();
+        writeln!(out, "{}", header.join("|").trim_end())?;
+        let rule: Vec<String> = widths.iter().map(|w| "-".repeat(w + 2)).collect();
+        writeln!(out, "{}", rule.join("+"))?;
+        for row in &rows {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(c, w)| format!(" {:>w$} ", c, w = w))
+                .collect();
+            writeln!(out, "{}", cells.join("|").trim_end())?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_manager::AccountManager;
+    use crate::{Transaction, TxType};
+    use rust_decimal::Decimal;
+
+    fn acc_man() -> AccountManager {
+        let mut acc_man = AccountManager::default();
+        let tx = Transaction::new(TxType::Deposit, 1u16, 1u32, Some(Decimal::new(15, 1)));
+        assert!(acc_man.process_tx(&tx).is_ok());
+        acc_man
+    }
+
+    fn render(writer: &dyn AccountWriter) -> String {
+        let mut buf = Vec::new();
+        acc_man().write_accounts(writer, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn csv_writer() {
+        assert_eq!(
+            render(&CsvAccountWriter),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn json_writer() {
+        let json: serde_json::Value = serde_json::from_str(&render(&JsonAccountWriter)).unwrap();
+        assert_eq!(json[0]["client"], 1);
+        assert_eq!(json[0]["available"], "1.5");
+        assert_eq!(json[0]["locked"], false);
+    }
+
+    #[test]
+    fn table_writer() {
+        assert_eq!(
+            render(&TableAccountWriter),
+            concat!(
+                " client | available | held | total | locked\n",
+                "--------+-----------+------+-------+--------\n",
+                "      1 |       1.5 |    0 |   1.5 |  false\n",
+            )
+        );
+    }
+
+    #[test]
+    fn csv_writer_adds_currency_column() {
+        let mut acc_man = acc_man();
+        let tx = Transaction::new(TxType::Deposit, 1u16, 2u32, Some(Decimal::new(2, 0)))
+            .with_currency(Currency::USD);
+        assert!(acc_man.process_tx(&tx).is_ok());
+        assert_eq!(
+            acc_man.to_string(),
+            "client,currency,available,held,total,locked\n1,,1.5,0,1.5,false\n1,USD,2,0,2,false\n"
+        );
+    }
+
+    #[test]
+    fn display_writes_csv_into_formatter() {
+        assert_eq!(
+            acc_man().to_string(),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use crate::Currency;
+use crate::LedgerEvent;
+use crate::TxType;
+use rust_decimal::Decimal;
+
+/// A client's funds in one currency. Every mutation keeps `total == available + held`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Balance {
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+}
+
+impl Balance {
+    fn new(currency: Option<Currency>) -> Self {
+        Balance {
+            currency,
+            available: Decimal::new(0, 0),
+            held: Decimal::new(0, 0),
+            total: Decimal::new(0, 0),
+        }
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.total == self.available + self.held
+    }
+
+    /// Credits available funds.
+    fn deposit(&mut self, amount: Decimal) {
+        self.available += amount;
+        self.total += amount;
+    }
+
+    /// Debits available funds.
+    fn withdraw(&mut self, amount: Decimal) {
+        self.available -= amount;
+        self.total -= amount;
+    }
+
+    /// Moves funds from available to held.
+    fn hold(&mut self, amount: Decimal) {
+        self.available -= amount;
+        self.held += amount;
+    }
+
+    /// Moves funds from held back to available.
+    fn release(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.available += amount;
+    }
+
+    /// Credits held funds, e.g. when a withdrawal is disputed.
+    fn credit_held(&mut self, amount: Decimal) {
+        self.held += amount;
+        self.total += amount;
+    }
+
+    /// Debits held funds, e.g. when a deposit is charged back.
+    fn debit_held(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.total -= amount;
+    }
+}
+
+/// Balances of a single client, one per currency they have transacted in.
+/// Balances only change by applying `LedgerEvent`s, so an account is a fold over its events.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClientAccount {
+    client: u16,
+    /// Ordered by currency, transactions without one first.
+    balances: Vec<Balance>,
+    locked: bool,
+}
+
+impl ClientAccount {
+    pub fn new(client: u16) -> Self {
+        ClientAccount {
+            client,
+            balances: Vec::new(),
+            locked: false,
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// The balance in `currency`, zero if the client never used it.
+    pub fn balance(&self, currency: Option<Currency>) -> Balance {
+        self.balances
+            .iter()
+            .find(|b| b.currency == currency)
+            .copied()
+            .unwrap_or_else(|| Balance::new(currency))
+    }
+
+    pub fn balances(&self) -> &[Balance] {
+        &self.balances
+    }
+
+    /// Available funds from transactions without a currency.
+    pub fn available(&self) -> Decimal {
+        self.balance(None).available
+    }
+
+    /// Held funds from transactions without a currency.
+    pub fn held(&self) -> Decimal {
+        self.balance(None).held
+    }
+
+    /// Total funds from transactions without a currency.
+    pub fn total(&self) -> Decimal {
+        self.balance(None).total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.balances.iter().all(Balance::is_balanced)
+    }
+
+    /// Replays a client's events from an empty account.
+    pub fn from_events<'a>(client: u16, events: impl IntoIterator<Item = &'a LedgerEvent>) -> Self {
+        events
+            .into_iter()
+            .fold(ClientAccount::new(client), |mut account, event| {
+                account.apply(event);
+                account
+            })
+    }
+
+    pub(crate) fn apply(&mut self, event: &LedgerEvent) {
+        let currency = event.currency();
+        match *event {
+            LedgerEvent::Deposited { amount, .. } => self.balance_mut(currency).deposit(amount),
+            LedgerEvent::Withdrew { amount, .. } => self.balance_mut(currency).withdraw(amount),
+            // The withdrawn funds are provisionally credited back as held
+            LedgerEvent::FundsHeld {
+                amount,
+                tx_type: TxType::Withdraw,
+                ..
+            } => self.balance_mut(currency).credit_held(amount),
+            LedgerEvent::FundsHeld { amount, .. } => self.balance_mut(currency).hold(amount),
+            // The withdrawal stands, so the provisional credit is dropped
+            LedgerEvent::FundsReleased {
+                amount,
+                tx_type: TxType::Withdraw,
+                ..
+            } => self.balance_mut(currency).debit_held(amount),
+            LedgerEvent::FundsReleased { amount, .. } => self.balance_mut(currency).release(amount),
+            // The withdrawal is reversed & the funds returned to the client
+            LedgerEvent::ChargedBack {
+                amount,
+                tx_type: TxType::Withdraw,
+                ..
+            } => self.balance_mut(currency).release(amount),
+            LedgerEvent::ChargedBack { amount, .. } => {
+                self.balance_mut(currency).debit_held(amount)
+            }
+            LedgerEvent::AccountLocked { .. } => self.locked = true,
+        }
+    }
+
+    fn balance_mut(&mut self, currency: Option<Currency>) -> &mut Balance {
+        let i = match self
+            .balances
+            .binary_search_by_key(&currency, |b| b.currency)
+        {
+            Ok(i) => i,
+            Err(i) => {
+                self.balances.insert(i, Balance::new(currency));
+                i
+            }
+        };
+        &mut self.balances[i]
+    }
+}
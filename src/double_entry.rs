@@ -0,0 +1,328 @@
+use crate::Currency;
+use crate::LedgerEvent;
+use crate::TxType;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// An account in the general ledger. Each client has one, holding their available funds,
+/// while held funds sit in the dispute suspense account until the dispute is settled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BookAccount {
+    /// Funds received by deposits.
+    CashIn,
+    /// Funds paid out by withdrawals.
+    CashOut,
+    /// Funds held while a transaction is disputed.
+    DisputeSuspense,
+    /// Funds clawed back from clients by chargebacks.
+    ChargebackLoss,
+    Client(u16),
+}
+
+impl fmt::Display for BookAccount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BookAccount::CashIn => f.write_str("cash-in"),
+            BookAccount::CashOut => f.write_str("cash-out"),
+            BookAccount::DisputeSuspense => f.write_str("dispute-suspense"),
+            BookAccount::ChargebackLoss => f.write_str("chargeback-loss"),
+            BookAccount::Client(client) => write!(f, "client-{}", client),
+        }
+    }
+}
+
+/// Moves `amount` from the `credit` account to the `debit` account, so every posting
+/// balances on its own. Each currency is kept in books of its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Posting {
+    pub tx: u32,
+    pub debit: BookAccount,
+    pub credit: BookAccount,
+    pub amount: Decimal,
+    pub currency: Option<Currency>,
+}
+
+impl Posting {
+    /// The postings which record an event in the general ledger.
+    pub fn for_event(event: &LedgerEvent) -> Vec<Posting> {
+        let (tx, debit, credit, amount) = match *event {
+            LedgerEvent::Deposited {
+                client, tx, amount, ..
+            } => (tx, BookAccount::CashIn, BookAccount::Client(client), amount),
+            LedgerEvent::Withdrew {
+                client, tx, amount, ..
+            } => (
+                tx,
+                BookAccount::Client(client),
+                BookAccount::CashOut,
+                amount,
+            ),
+            LedgerEvent::FundsHeld {
+                client,
+                tx,
+                amount,
+                ref tx_type,
+                ..
+            } => {
+                // A disputed withdrawal is provisionally reversed into held funds
+                let from = match tx_type {
+                    TxType::Withdraw => BookAccount::CashOut,
+                    _ => BookAccount::Client(client),
+                };
+                (tx, from, BookAccount::DisputeSuspense, amount)
+            }
+            LedgerEvent::FundsReleased {
+                client,
+                tx,
+                amount,
+                ref tx_type,
+                ..
+            } => {
+                let to = match tx_type {
+                    TxType::Withdraw => BookAccount::CashOut,
+                    _ => BookAccount::Client(client),
+                };
+                (tx, BookAccount::DisputeSuspense, to, amount)
+            }
+            LedgerEvent::ChargedBack {
+                client,
+                tx,
+                amount,
+                ref tx_type,
+                ..
+            } => {
+                // A reversed withdrawal is returned to the client
+                let to = match tx_type {
+                    TxType::Withdraw => BookAccount::Client(client),
+                    _ => BookAccount::ChargebackLoss,
+                };
+                (tx, BookAccount::DisputeSuspense, to, amount)
+            }
+            LedgerEvent::AccountLocked { .. } => return Vec::new(),
+        };
+        vec![Posting {
+            tx,
+            debit,
+            credit,
+            amount,
+            currency: event.currency(),
+        }]
+    }
+}
+
+/// The balance of every account posted to in each currency, debits counted as positive &
+/// credits as negative. The books balance when the balances of each currency sum to zero.
+#[derive(Debug, Default, PartialEq)]
+pub struct TrialBalance {
+    balances: BTreeMap<(Option<Currency>, BookAccount), Decimal>,
+}
+
+impl TrialBalance {
+    pub fn post(&mut self, posting: &Posting) {
+        *self
+            .balances
+            .entry((posting.currency, posting.debit))
+            .or_default() += posting.amount;
+        *self
+            .balances
+            .entry((posting.currency, posting.credit))
+            .or_default() -= posting.amount;
+    }
+
+    pub fn balance(&self, currency: Option<Currency>, account: BookAccount) -> Decimal {
+        self.balances
+            .get(&(currency, account))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The sum of every balance in `currency`, zero when its books balance.
+    pub fn difference(&self, currency: Option<Currency>) -> Decimal {
+        self.balances
+            .iter()
+            .filter(|((c, _), _)| *c == currency)
+            .map(|(_, balance)| balance)
+            .sum()
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.currencies().all(|c| self.difference(c).is_zero())
+    }
+
+    fn currencies(&self) -> impl Iterator<Item = Option<Currency>> + '_ {
+        let mut currencies: Vec<Option<Currency>> = self.balances.keys().map(|k| k.0).collect();
+        currencies.dedup();
+        currencies.into_iter()
+    }
+
+    /// Writes a CSV report with each account's balance in the debit or credit column,
+    /// followed by the totals of both columns. With more than one currency, each has its
+    /// own totals & a `currency` column is added.
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let zero = Decimal::new(0, 0);
+        let with_currency = self.currencies().any(|c| c.is_some());
+        let currency_cell = |currency: Option<Currency>| match (with_currency, currency) {
+            (false, _) => String::new(),
+            (true, Some(c)) => format!("{},", c),
+            (true, None) => ",".to_string(),
+        };
+        writeln!(
+            out,
+            "account,{}debit,credit",
+            if with_currency { "currency," } else { "" }
+        )?;
+        for currency in self.currencies() {
+            let (mut debits, mut credits) = (zero, zero);
+            for ((_, account), balance) in self.balances.iter().filter(|(k, _)| k.0 == currency) {
+                let (debit, credit) = if balance.is_sign_negative() {
+                    (zero, -balance)
+                } else {
+                    (*balance, zero)
+                };
+                debits += debit;
+                credits += credit;
+                writeln!(
+                    out,
+                    "{},{}{},{}",
+                    account,
+                    currency_cell(currency),
+                    debit,
+                    credit
+                )?;
+            }
+            writeln!(
+                out,
+                "total,{}{},{}",
+                currency_cell(currency),
+                debits,
+                credits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Extend<&'a Posting> for TrialBalance {
+    fn extend<I: IntoIterator<Item = &'a Posting>>(&mut self, postings: I) {
+        for posting in postings {
+            self.post(posting);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_manager::{AccountManager, DisputePolicy};
+    use crate::Transaction;
+
+    fn run(policy: DisputePolicy, txs: &[Transaction]) -> AccountManager {
+        let mut acc_man = AccountManager::with_dispute_policy(policy);
+        for tx in txs {
+            assert!(acc_man.process_tx(tx).is_ok());
+        }
+        acc_man
+    }
+
+    #[test]
+    fn deposit_chargeback_balances() {
+        let acc_man = run(
+            DisputePolicy::DepositsOnly,
+            &[
+                Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+                Transaction::new(TxType::Deposit, 1, 2, Some(Decimal::new(5, 0))),
+                Transaction::new(TxType::Withdraw, 1, 3, Some(Decimal::new(3, 0))),
+                Transaction::new(TxType::Dispute, 1, 2, None),
+                Transaction::new(TxType::Chargeback, 1, 2, None),
+            ],
+        );
+        let trial_balance = acc_man.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(
+            trial_balance.balance(None, BookAccount::CashIn),
+            Decimal::new(15, 0)
+        );
+        assert_eq!(
+            trial_balance.balance(None, BookAccount::CashOut),
+            Decimal::new(-3, 0)
+        );
+        assert_eq!(
+            trial_balance.balance(None, BookAccount::ChargebackLoss),
+            Decimal::new(-5, 0)
+        );
+        assert!(trial_balance
+            .balance(None, BookAccount::DisputeSuspense)
+            .is_zero());
+        // A client's account is credited with what the bank owes them
+        assert_eq!(
+            -trial_balance.balance(None, BookAccount::Client(1)),
+            acc_man.account(1).unwrap().unwrap().available()
+        );
+    }
+
+    #[test]
+    fn open_withdrawal_dispute_sits_in_suspense() {
+        let acc_man = run(
+            DisputePolicy::DepositsAndWithdrawals,
+            &[
+                Transaction::new(TxType::Deposit, 2, 1, Some(Decimal::new(10, 0))),
+                Transaction::new(TxType::Withdraw, 2, 2, Some(Decimal::new(4, 0))),
+                Transaction::new(TxType::Dispute, 2, 2, None),
+            ],
+        );
+        let trial_balance = acc_man.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(
+            -trial_balance.balance(None, BookAccount::DisputeSuspense),
+            acc_man.account(2).unwrap().unwrap().held()
+        );
+        assert!(trial_balance.balance(None, BookAccount::CashOut).is_zero());
+    }
+
+    #[test]
+    fn currencies_balance_separately() {
+        let acc_man = run(
+            DisputePolicy::DepositsOnly,
+            &[
+                Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0)))
+                    .with_currency(Currency::EUR),
+                Transaction::new(TxType::Deposit, 1, 2, Some(Decimal::new(7, 0)))
+                    .with_currency(Currency::GBP),
+                Transaction::new(TxType::Dispute, 1, 2, None),
+            ],
+        );
+        let trial_balance = acc_man.trial_balance().unwrap();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(
+            trial_balance.balance(Some(Currency::GBP), BookAccount::DisputeSuspense),
+            Decimal::new(-7, 0)
+        );
+        assert!(trial_balance
+            .balance(Some(Currency::EUR), BookAccount::DisputeSuspense)
+            .is_zero());
+        let mut out = Vec::new();
+        trial_balance.write(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("total,GBP,7,7\n"));
+    }
+
+    #[test]
+    fn report_totals_match() {
+        let acc_man = run(
+            DisputePolicy::DepositsOnly,
+            &[Transaction::new(
+                TxType::Deposit,
+                1,
+                1,
+                Some(Decimal::new(15, 1)),
+            )],
+        );
+        let mut out = Vec::new();
+        acc_man.trial_balance().unwrap().write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "account,debit,credit\ncash-in,1.5,0\nclient-1,0,1.5\ntotal,1.5,1.5\n"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use crate::account_manager::{with_ledger, AccountManager};
+use crate::client_account::Balance;
+use crate::ledger_store::StoreError;
+use crate::ClientAccount;
+use crate::Currency;
+use crate::DisputeState;
+use crate::LedgerError;
+use crate::LedgerEvent;
+use crate::Transaction;
+use crate::TxType;
+use rust_decimal::Decimal;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+/// The types & client generated from `proto/ledger.proto`.
+pub mod proto {
+    tonic::include_proto!("ledger");
+}
+
+use proto::ledger_server::{Ledger, LedgerServer};
+
+/// The metadata key rejections carry the kind of their `LedgerError` under.
+pub const ERROR_KIND_KEY: &str = "ledger-error-kind";
+
+/// The metadata key a submitted transaction's `TxOutcome` is returned under, `applied` or
+/// `replayed` when an identical transaction was already applied.
+pub const OUTCOME_KEY: &str = "ledger-outcome";
+
+impl From<TxType> for proto::TxType {
+    fn from(tx_type: TxType) -> Self {
+        match tx_type {
+            TxType::Deposit => proto::TxType::Deposit,
+            TxType::Withdraw => proto::TxType::Withdraw,
+            TxType::Dispute => proto::TxType::Dispute,
+            TxType::Resolve => proto::TxType::Resolve,
+            TxType::Chargeback => proto::TxType::Chargeback,
+        }
+    }
+}
+
+fn tx_type_of(value: i32) -> Result<Option<TxType>, Status> {
+    match proto::TxType::try_from(value) {
+        Ok(proto::TxType::Unspecified) => Ok(None),
+        Ok(proto::TxType::Deposit) => Ok(Some(TxType::Deposit)),
+        Ok(proto::TxType::Withdraw) => Ok(Some(TxType::Withdraw)),
+        Ok(proto::TxType::Dispute) => Ok(Some(TxType::Dispute)),
+        Ok(proto::TxType::Resolve) => Ok(Some(TxType::Resolve)),
+        Ok(proto::TxType::Chargeback) => Ok(Some(TxType::Chargeback)),
+        Err(_) => Err(Status::invalid_argument(format!(
+            "Unknown transaction type {}",
+            value
+        ))),
+    }
+}
+
+impl From<Option<Currency>> for proto::Currency {
+    fn from(currency: Option<Currency>) -> Self {
+        match currency {
+            None => proto::Currency::Unspecified,
+            Some(Currency::EUR) => proto::Currency::Eur,
+            Some(Currency::GBP) => proto::Currency::Gbp,
+            Some(Currency::USD) => proto::Currency::Usd,
+        }
+    }
+}
+
+fn currency_of(value: i32) -> Result<Option<Currency>, Status> {
+    match proto::Currency::try_from(value) {
+        Ok(proto::Currency::Unspecified) => Ok(None),
+        Ok(proto::Currency::Eur) => Ok(Some(Currency::EUR)),
+        Ok(proto::Currency::Gbp) => Ok(Some(Currency::GBP)),
+        Ok(proto::Currency::Usd) => Ok(Some(Currency::USD)),
+        Err(_) => Err(Status::invalid_argument(format!(
+            "Unknown currency {}",
+            value
+        ))),
+    }
+}
+
+/// Rejects fields which cannot be represented, leaving the rest to the `AccountManager`.
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = Status;
+
+    fn try_from(tx: proto::Transaction) -> Result<Self, Status> {
+        let client = u16::try_from(tx.client).map_err(|_| {
+            Status::invalid_argument(format!("Client {} is out of range", tx.client))
+        })?;
+        let amount = match tx.amount {
+            Some(amount) => Some(Decimal::from_str(amount.trim()).map_err(|e| {
+                Status::invalid_argument(format!("Invalid amount {:?}: {}", amount, e))
+            })?),
+            None => None,
+        };
+        let mut tx = Transaction {
+            tx_type: tx_type_of(tx.r#type)?,
+            client,
+            tx: tx.tx,
+            amount,
+            timestamp: None,
+            currency: currency_of(tx.currency)?,
+            action: tx.action,
+            dispute_state: DisputeState::None,
+            rejection: None,
+        };
+        tx.round_amount();
+        Ok(tx)
+    }
+}
+
+impl From<&Balance> for proto::Balance {
+    fn from(balance: &Balance) -> Self {
+        proto::Balance {
+            currency: proto::Currency::from(balance.currency()).into(),
+            available: balance.available().to_string(),
+            held: balance.held().to_string(),
+            total: balance.total().to_string(),
+        }
+    }
+}
+
+impl From<&ClientAccount> for proto::Account {
+    fn from(account: &ClientAccount) -> Self {
+        proto::Account {
+            client: account.client().into(),
+            balances: account
+                .balances()
+                .iter()
+                .map(proto::Balance::from)
+                .collect(),
+            locked: account.locked(),
+        }
+    }
+}
+
+impl From<&LedgerEvent> for proto::Event {
+    fn from(event: &LedgerEvent) -> Self {
+        let (kind, amount, tx_type) = match event {
+            LedgerEvent::Deposited { amount, .. } => {
+                (proto::EventKind::Deposited, Some(amount), None)
+            }
+            LedgerEvent::Withdrew { amount, .. } => {
+                (proto::EventKind::Withdrew, Some(amount), None)
+            }
+            LedgerEvent::FundsHeld {
+                amount, tx_type, ..
+            } => (proto::EventKind::FundsHeld, Some(amount), Some(tx_type)),
+            LedgerEvent::FundsReleased {
+                amount, tx_type, ..
+            } => (proto::EventKind::FundsReleased, Some(amount), Some(tx_type)),
+            LedgerEvent::ChargedBack {
+                amount, tx_type, ..
+            } => (proto::EventKind::ChargedBack, Some(amount), Some(tx_type)),
+            LedgerEvent::AccountLocked { .. } => (proto::EventKind::AccountLocked, None, None),
+        };
+        proto::Event {
+            kind: kind.into(),
+            client: event.client().into(),
+            tx: event.tx(),
+            amount: amount.map(Decimal::to_string),
+            tx_type: tx_type
+                .cloned()
+                .map_or(proto::TxType::Unspecified, proto::TxType::from)
+                .into(),
+            currency: proto::Currency::from(event.currency()).into(),
+            action: event.action(),
+        }
+    }
+}
+
+/// The status a rejected transaction is reported with, carrying the kind of error in its
+/// metadata so clients need not parse the message.
+pub fn status_of(e: &LedgerError) -> Status {
+    let message = e.to_string();
+    let mut status = match e {
+        LedgerError::MissingTxType { .. }
+        | LedgerError::AmountRequired { .. }
+        | LedgerError::NegativeAmount { .. }
+        | LedgerError::CurrencyMismatch { .. } => Status::invalid_argument(message),
+        LedgerError::InsufficientFunds { .. }
+        | LedgerError::NotDisputable { .. }
+        | LedgerError::InvalidDisputeTransition { .. }
+        | LedgerError::AccountLocked { .. } => Status::failed_precondition(message),
+        LedgerError::TransactionConflict { .. } | LedgerError::ActionConflict { .. } => {
+            Status::already_exists(message)
+        }
+        LedgerError::AccountNotFound { .. } | LedgerError::TransactionNotFound { .. } => {
+            Status::not_found(message)
+        }
+        LedgerError::ClientMismatch { .. } => Status::permission_denied(message),
+        LedgerError::Storage { .. } => Status::internal(message),
+    };
+    status
+        .metadata_mut()
+        .insert(ERROR_KIND_KEY, e.kind().parse().unwrap());
+    status
+}
+
+fn storage_status(e: StoreError) -> Status {
+    Status::internal(e.to_string())
+}
+
+/// Implements the `Ledger` service over an `AccountManager` shared with any other servers.
+pub struct LedgerService {
+    acc_man: Arc<Mutex<AccountManager>>,
+}
+
+impl LedgerService {
+    pub fn new(acc_man: Arc<Mutex<AccountManager>>) -> Self {
+        LedgerService { acc_man }
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Ledger for LedgerService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let tx = Transaction::try_from(request.into_inner())?;
+        let (client, tx_id) = (tx.client, tx.tx);
+        let (outcome, account) = with_ledger(&self.acc_man, move |acc_man| {
+            let outcome = acc_man.process_tx(&tx).map_err(|e| status_of(&e))?;
+            let account = acc_man.account(tx.client).map_err(storage_status)?;
+            Ok::<_, Status>((outcome, account))
+        })
+        .await?;
+        match account {
+            Some(account) => {
+                let mut response = Response::new(proto::Account::from(&account));
+                response
+                    .metadata_mut()
+                    .insert(OUTCOME_KEY, outcome.to_string().parse().unwrap());
+                Ok(response)
+            }
+            None => Err(Status::internal(format!(
+                "No account for client {} after transaction {}",
+                client, tx_id
+            ))),
+        }
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client = request.into_inner().client;
+        let account = match u16::try_from(client) {
+            Ok(client) => with_ledger(&self.acc_man, move |acc_man| acc_man.account(client))
+                .await
+                .map_err(storage_status)?,
+            Err(_) => None,
+        };
+        match account {
+            Some(account) => Ok(Response::new(proto::Account::from(&account))),
+            None => Err(Status::not_found(format!(
+                "No account for client {}",
+                client
+            ))),
+        }
+    }
+
+    async fn list_accounts(
+        &self,
+        _request: Request<proto::ListAccountsRequest>,
+    ) -> Result<Response<proto::ListAccountsResponse>, Status> {
+        let accounts = with_ledger(&self.acc_man, |acc_man| acc_man.accounts())
+            .await
+            .map_err(storage_status)?;
+        Ok(Response::new(proto::ListAccountsResponse {
+            accounts: accounts.iter().map(proto::Account::from).collect(),
+        }))
+    }
+
+    type StreamEventsStream = EventStream;
+
+    /// The history & the subscription are taken under one lock, so no event is missed or
+    /// sent twice between them. The history of every client is grouped by client, as the
+    /// store keeps it, rather than in the order the events were recorded.
+    async fn stream_events(
+        &self,
+        request: Request<proto::StreamEventsRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let request = request.into_inner();
+        let client = match request.client.map(u16::try_from) {
+            Some(Ok(client)) => Some(client),
+            Some(Err(_)) => return Err(Status::invalid_argument("Client is out of range")),
+            None => None,
+        };
+        let follow = request.follow;
+        let (history, subscription) = with_ledger(&self.acc_man, move |acc_man| {
+            let subscription = follow.then(|| acc_man.subscribe());
+            let history = match client {
+                Some(client) => acc_man.events_for_client(client),
+                None => acc_man.events(),
+            };
+            (history, subscription)
+        })
+        .await;
+        let history = history.map_err(storage_status)?;
+        let history = tokio_stream::iter(history);
+        let stream: EventStream = match subscription {
+            None => Box::pin(history.map(|event| Ok(proto::Event::from(&event)))),
+            Some(subscription) => {
+                let live = BroadcastStream::new(subscription)
+                    .filter(move |event| match (client, event) {
+                        (Some(client), Ok(event)) => event.client() == client,
+                        _ => true,
+                    })
+                    .map(|event| match event {
+                        Ok(event) => Ok(event),
+                        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                            Err(Status::resource_exhausted(format!(
+                                "Fell behind & missed {} events",
+                                missed
+                            )))
+                        }
+                    });
+                Box::pin(
+                    history
+                        .map(Ok)
+                        .chain(live)
+                        .map(|event| event.map(|event| proto::Event::from(&event))),
+                )
+            }
+        };
+        Ok(Response::new(stream))
+    }
+}
+
+/// Serves the `Ledger` service on `listener` until ctrl-c is pressed.
+pub async fn serve(
+    acc_man: Arc<Mutex<AccountManager>>,
+    listener: TcpListener,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(LedgerServer::new(LedgerService::new(acc_man)))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+}
@@ -0,0 +1,246 @@
+use crate::account_manager::{with_ledger, AccountManager, TxOutcome};
+use crate::ledger_store::{StoreError, StoredTransaction};
+use crate::LedgerError;
+use crate::Transaction;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+/// The body of every error response.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorBody {
+    pub kind: String,
+    pub error: String,
+}
+
+struct ApiError(StatusCode, ErrorBody);
+
+impl ApiError {
+    fn not_found(kind: &str, error: String) -> Self {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            ErrorBody {
+                kind: kind.to_string(),
+                error,
+            },
+        )
+    }
+}
+
+impl From<LedgerError> for ApiError {
+    fn from(e: LedgerError) -> Self {
+        ApiError(
+            status_of(&e),
+            ErrorBody {
+                kind: e.kind().to_string(),
+                error: e.to_string(),
+            },
+        )
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(e: StoreError) -> Self {
+        ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorBody {
+                kind: "StorageError".to_string(),
+                error: e.to_string(),
+            },
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(self.1)).into_response()
+    }
+}
+
+/// The status code a rejected transaction is reported with.
+pub fn status_of(e: &LedgerError) -> StatusCode {
+    match e {
+        LedgerError::MissingTxType { .. }
+        | LedgerError::AmountRequired { .. }
+        | LedgerError::NegativeAmount { .. }
+        | LedgerError::InsufficientFunds { .. }
+        | LedgerError::NotDisputable { .. }
+        | LedgerError::CurrencyMismatch { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        LedgerError::TransactionConflict { .. }
+        | LedgerError::ActionConflict { .. }
+        | LedgerError::InvalidDisputeTransition { .. } => StatusCode::CONFLICT,
+        LedgerError::AccountLocked { .. } | LedgerError::ClientMismatch { .. } => {
+            StatusCode::FORBIDDEN
+        }
+        LedgerError::AccountNotFound { .. } | LedgerError::TransactionNotFound { .. } => {
+            StatusCode::NOT_FOUND
+        }
+        LedgerError::Storage { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Routes requests to an `AccountManager` shared by every connection.
+/// Transactions are applied one at a time, in the order their requests take the lock, on a
+/// blocking thread so the ledger's I/O does not stall the runtime.
+pub fn router(acc_man: Arc<Mutex<AccountManager>>) -> Router {
+    Router::new()
+        .route("/transactions", post(post_transaction))
+        .route("/transactions/{tx}", get(get_transaction))
+        .route("/accounts", get(get_accounts))
+        .route("/accounts/{client}", get(get_account))
+        .with_state(acc_man)
+}
+
+/// Serves the API on `listener` until ctrl-c is pressed.
+pub async fn serve(acc_man: Arc<Mutex<AccountManager>>, listener: TcpListener) -> io::Result<()> {
+    axum::serve(listener, router(acc_man))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+}
+
+/// Applies a transaction & responds with the client's account, with `200` rather than
+/// `201` when an identical transaction was already applied.
+async fn post_transaction(
+    State(acc_man): State<Arc<Mutex<AccountManager>>>,
+    Json(mut tx): Json<Transaction>,
+) -> Result<impl IntoResponse, ApiError> {
+    tx.round_amount();
+    with_ledger(&acc_man, move |acc_man| {
+        let status = match acc_man.process_tx(&tx)? {
+            TxOutcome::Applied => StatusCode::CREATED,
+            TxOutcome::Replayed => StatusCode::OK,
+        };
+        let account = acc_man.account(tx.client)?;
+        Ok((status, Json(account)))
+    })
+    .await
+}
+
+async fn get_transaction(
+    State(acc_man): State<Arc<Mutex<AccountManager>>>,
+    Path(tx): Path<u32>,
+) -> Result<impl IntoResponse, ApiError> {
+    match with_ledger(&acc_man, move |acc_man| acc_man.transaction(tx)).await? {
+        Some(t) => Ok(Json(StoredTransaction::from(t))),
+        None => Err(ApiError::not_found(
+            "TransactionNotFound",
+            format!("No transaction {}", tx),
+        )),
+    }
+}
+
+async fn get_accounts(
+    State(acc_man): State<Arc<Mutex<AccountManager>>>,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok(Json(
+        with_ledger(&acc_man, |acc_man| acc_man.accounts()).await?,
+    ))
+}
+
+async fn get_account(
+    State(acc_man): State<Arc<Mutex<AccountManager>>>,
+    Path(client): Path<u16>,
+) -> Result<impl IntoResponse, ApiError> {
+    match with_ledger(&acc_man, move |acc_man| acc_man.account(client)).await? {
+        Some(account) => Ok(Json(account)),
+        None => Err(ApiError::not_found(
+            "AccountNotFound",
+            format!("No account for client {}", client),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    async fn send(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.unwrap_or_default().to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    fn app() -> Router {
+        router(Arc::new(Mutex::new(AccountManager::default())))
+    }
+
+    #[tokio::test]
+    async fn posts_transactions_and_reads_them_back() {
+        let app = app();
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.50005"}"#;
+        let (status, account) = send(&app, "POST", "/transactions", Some(deposit)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(account["balances"][0]["available"], "2.5000");
+
+        let dispute = r#"{"type": "dispute", "client": 1, "tx": 1}"#;
+        let (status, _) = send(&app, "POST", "/transactions", Some(dispute)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, tx) = send(&app, "GET", "/transactions/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tx["dispute_state"], "Disputed");
+
+        let (status, accounts) = send(&app, "GET", "/accounts", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(accounts.as_array().unwrap().len(), 1);
+        let (status, account) = send(&app, "GET", "/accounts/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(account["balances"][0]["held"], "2.5000");
+    }
+
+    #[tokio::test]
+    async fn maps_ledger_errors_to_status_codes() {
+        let app = app();
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}"#;
+        send(&app, "POST", "/transactions", Some(deposit)).await;
+        let (status, account) = send(&app, "POST", "/transactions", Some(deposit)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(account["balances"][0]["available"], "1.0");
+        let conflict = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.0"}"#;
+        let (status, body) = send(&app, "POST", "/transactions", Some(conflict)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["kind"], "TransactionConflict");
+
+        let withdraw = r#"{"type": "withdraw", "client": 1, "tx": 2, "amount": "5.0"}"#;
+        let (status, body) = send(&app, "POST", "/transactions", Some(withdraw)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["kind"], "InsufficientFunds");
+
+        let deposit = r#"{"type": "deposit", "client": 2, "tx": 3, "amount": "1.0"}"#;
+        send(&app, "POST", "/transactions", Some(deposit)).await;
+        let dispute = r#"{"type": "dispute", "client": 2, "tx": 1}"#;
+        let (status, _) = send(&app, "POST", "/transactions", Some(dispute)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = send(&app, "GET", "/accounts/9", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["kind"], "AccountNotFound");
+        let (status, _) = send(&app, "GET", "/transactions/9", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
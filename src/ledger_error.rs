@@ -0,0 +1,237 @@
+use crate::Currency;
+use crate::DisputeState;
+use crate::TxType;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum LedgerError {
+    MissingTxType {
+        client: u16,
+        tx: u32,
+    },
+    AmountRequired {
+        client: u16,
+        tx: u32,
+    },
+    NegativeAmount {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+    },
+    TransactionConflict {
+        client: u16,
+        tx: u32,
+    },
+    ActionConflict {
+        client: u16,
+        tx: u32,
+        action: u32,
+    },
+    AccountLocked {
+        client: u16,
+        tx: u32,
+    },
+    InsufficientFunds {
+        client: u16,
+        tx: u32,
+        available: Decimal,
+        requested: Decimal,
+    },
+    AccountNotFound {
+        client: u16,
+        tx: u32,
+    },
+    TransactionNotFound {
+        client: u16,
+        tx: u32,
+    },
+    NotDisputable {
+        client: u16,
+        tx: u32,
+        tx_type: TxType,
+    },
+    InvalidDisputeTransition {
+        client: u16,
+        tx: u32,
+        from: DisputeState,
+        to: DisputeState,
+    },
+    ClientMismatch {
+        client: u16,
+        tx: u32,
+        owner: u16,
+    },
+    CurrencyMismatch {
+        client: u16,
+        tx: u32,
+        expected: Option<Currency>,
+        found: Currency,
+    },
+    Storage {
+        client: u16,
+        tx: u32,
+        error: String,
+    },
+}
+
+impl LedgerError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LedgerError::MissingTxType { .. } => "MissingTxType",
+            LedgerError::AmountRequired { .. } => "AmountRequired",
+            LedgerError::NegativeAmount { .. } => "NegativeAmount",
+            LedgerError::TransactionConflict { .. } => "TransactionConflict",
+            LedgerError::ActionConflict { .. } => "ActionConflict",
+            LedgerError::AccountLocked { .. } => "AccountLocked",
+            LedgerError::InsufficientFunds { .. } => "InsufficientFunds",
+            LedgerError::AccountNotFound { .. } => "AccountNotFound",
+            LedgerError::TransactionNotFound { .. } => "TransactionNotFound",
+            LedgerError::NotDisputable { .. } => "NotDisputable",
+            LedgerError::InvalidDisputeTransition { .. } => "InvalidDisputeTransition",
+            LedgerError::ClientMismatch { .. } => "ClientMismatch",
+            LedgerError::CurrencyMismatch { .. } => "CurrencyMismatch",
+            LedgerError::Storage { .. } => "StorageError",
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        match *self {
+            LedgerError::MissingTxType { client, .. }
+            | LedgerError::AmountRequired { client, .. }
+            | LedgerError::NegativeAmount { client, .. }
+            | LedgerError::TransactionConflict { client, .. }
+            | LedgerError::ActionConflict { client, .. }
+            | LedgerError::AccountLocked { client, .. }
+            | LedgerError::InsufficientFunds { client, .. }
+            | LedgerError::AccountNotFound { client, .. }
+            | LedgerError::TransactionNotFound { client, .. }
+            | LedgerError::NotDisputable { client, .. }
+            | LedgerError::InvalidDisputeTransition { client, .. }
+            | LedgerError::ClientMismatch { client, .. }
+            | LedgerError::CurrencyMismatch { client, .. }
+            | LedgerError::Storage { client, .. } => client,
+        }
+    }
+
+    pub fn tx(&self) -> u32 {
+        match *self {
+            LedgerError::MissingTxType { tx, .. }
+            | LedgerError::AmountRequired { tx, .. }
+            | LedgerError::NegativeAmount { tx, .. }
+            | LedgerError::TransactionConflict { tx, .. }
+            | LedgerError::ActionConflict { tx, .. }
+            | LedgerError::AccountLocked { tx, .. }
+            | LedgerError::InsufficientFunds { tx, .. }
+            | LedgerError::AccountNotFound { tx, .. }
+            | LedgerError::TransactionNotFound { tx, .. }
+            | LedgerError::NotDisputable { tx, .. }
+            | LedgerError::InvalidDisputeTransition { tx, .. }
+            | LedgerError::ClientMismatch { tx, .. }
+            | LedgerError::CurrencyMismatch { tx, .. }
+            | LedgerError::Storage { tx, .. } => tx,
+        }
+    }
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::MissingTxType { client, tx } => {
+                write!(f, "No Tx Type provided (client {}, tx {})", client, tx)
+            }
+            LedgerError::AmountRequired { client, tx } => {
+                write!(f, "Amount Required (client {}, tx {})", client, tx)
+            }
+            LedgerError::NegativeAmount { client, tx, amount } => write!(
+                f,
+                "Cannot process a Negative Amount {} (client {}, tx {})",
+                amount, client, tx
+            ),
+            LedgerError::TransactionConflict { client, tx } => write!(
+                f,
+                "Transaction id already used by a different transaction (client {}, tx {})",
+                client, tx
+            ),
+            LedgerError::ActionConflict { client, tx, action } => write!(
+                f,
+                "Action {} already used by a different action (client {}, tx {})",
+                action, client, tx
+            ),
+            LedgerError::AccountLocked { client, tx } => write!(
+                f,
+                "Account Locked due to Chargeback (client {}, tx {})",
+                client, tx
+            ),
+            LedgerError::InsufficientFunds {
+                client,
+                tx,
+                available,
+                requested,
+            } => write!(
+                f,
+                "Insufficient Funds, requested {} but only {} available (client {}, tx {})",
+                requested, available, client, tx
+            ),
+            LedgerError::AccountNotFound { client, tx } => write!(
+                f,
+                "No Associated Client Account Found (client {}, tx {})",
+                client, tx
+            ),
+            LedgerError::TransactionNotFound { client, tx } => write!(
+                f,
+                "No Associated Transaction Found (client {}, tx {})",
+                client, tx
+            ),
+            LedgerError::NotDisputable {
+                client,
+                tx,
+                tx_type,
+            } => write!(
+                f,
+                "A {:?} cannot be disputed (client {}, tx {})",
+                tx_type, client, tx
+            ),
+            LedgerError::InvalidDisputeTransition {
+                client,
+                tx,
+                from,
+                to,
+            } => write!(
+                f,
+                "Transaction cannot move from {:?} to {:?} (client {}, tx {})",
+                from, to, client, tx
+            ),
+            LedgerError::ClientMismatch { client, tx, owner } => write!(
+                f,
+                "Transaction belongs to client {} (client {}, tx {})",
+                owner, client, tx
+            ),
+            LedgerError::CurrencyMismatch {
+                client,
+                tx,
+                expected,
+                found,
+            } => match expected {
+                Some(expected) => write!(
+                    f,
+                    "Transaction is in {}, not {} (client {}, tx {})",
+                    expected, found, client, tx
+                ),
+                None => write!(
+                    f,
+                    "Transaction has no currency, not {} (client {}, tx {})",
+                    found, client, tx
+                ),
+            },
+            LedgerError::Storage { client, tx, error } => write!(
+                f,
+                "Ledger storage failed: {} (client {}, tx {})",
+                error, client, tx
+            ),
+        }
+    }
+}
+
+impl Error for LedgerError {}
@@ -0,0 +1,124 @@
+use crate::Currency;
+use crate::TxType;
+use rust_decimal::Decimal;
+
+/// A change to a client's account. Accounts are only ever changed by applying events,
+/// which are kept in an append-only journal, so an account's history explains its state.
+/// Events about a dispute carry the type of the disputed transaction, as disputed
+/// withdrawals move funds differently to disputed deposits, & the currency of the disputed
+/// transaction, as a dispute always settles in it. They also carry the action id of the
+/// dispute, resolve or chargeback which caused them, when it was given one.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "event")]
+pub enum LedgerEvent {
+    Deposited {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    Withdrew {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    FundsHeld {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+        tx_type: TxType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<u32>,
+    },
+    FundsReleased {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+        tx_type: TxType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<u32>,
+    },
+    ChargedBack {
+        client: u16,
+        tx: u32,
+        amount: Decimal,
+        tx_type: TxType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<u32>,
+    },
+    AccountLocked {
+        client: u16,
+        tx: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        action: Option<u32>,
+    },
+}
+
+impl LedgerEvent {
+    pub fn client(&self) -> u16 {
+        match *self {
+            LedgerEvent::Deposited { client, .. }
+            | LedgerEvent::Withdrew { client, .. }
+            | LedgerEvent::FundsHeld { client, .. }
+            | LedgerEvent::FundsReleased { client, .. }
+            | LedgerEvent::ChargedBack { client, .. }
+            | LedgerEvent::AccountLocked { client, .. } => client,
+        }
+    }
+
+    pub fn tx(&self) -> u32 {
+        match *self {
+            LedgerEvent::Deposited { tx, .. }
+            | LedgerEvent::Withdrew { tx, .. }
+            | LedgerEvent::FundsHeld { tx, .. }
+            | LedgerEvent::FundsReleased { tx, .. }
+            | LedgerEvent::ChargedBack { tx, .. }
+            | LedgerEvent::AccountLocked { tx, .. } => tx,
+        }
+    }
+
+    /// The action id of the dispute, resolve or chargeback which caused the event.
+    pub fn action(&self) -> Option<u32> {
+        match *self {
+            LedgerEvent::FundsHeld { action, .. }
+            | LedgerEvent::FundsReleased { action, .. }
+            | LedgerEvent::ChargedBack { action, .. }
+            | LedgerEvent::AccountLocked { action, .. } => action,
+            LedgerEvent::Deposited { .. } | LedgerEvent::Withdrew { .. } => None,
+        }
+    }
+
+    /// The type of transaction which caused the event.
+    pub fn caused_by(&self) -> TxType {
+        match self {
+            LedgerEvent::Deposited { .. } => TxType::Deposit,
+            LedgerEvent::Withdrew { .. } => TxType::Withdraw,
+            LedgerEvent::FundsHeld { .. } => TxType::Dispute,
+            LedgerEvent::FundsReleased { .. } => TxType::Resolve,
+            LedgerEvent::ChargedBack { .. } | LedgerEvent::AccountLocked { .. } => {
+                TxType::Chargeback
+            }
+        }
+    }
+
+    /// The currency of the balance changed, `None` for locking the account.
+    pub fn currency(&self) -> Option<Currency> {
+        match *self {
+            LedgerEvent::Deposited { currency, .. }
+            | LedgerEvent::Withdrew { currency, .. }
+            | LedgerEvent::FundsHeld { currency, .. }
+            | LedgerEvent::FundsReleased { currency, .. }
+            | LedgerEvent::ChargedBack { currency, .. } => currency,
+            LedgerEvent::AccountLocked { .. } => None,
+        }
+    }
+}
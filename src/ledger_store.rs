@@ -0,0 +1,640 @@
+use crate::account_manager::shard_of;
+use crate::ClientAccount;
+use crate::Currency;
+use crate::DisputeState;
+use crate::LedgerError;
+use crate::LedgerEvent;
+use crate::Transaction;
+use crate::TxType;
+use rust_decimal::Decimal;
+use sled::transaction::{TransactionError, Transactional};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+/// Where the `AccountManager` keeps account balances & the transactions which may later
+/// be disputed. Values are read, changed & written back, so a store only needs get & put.
+pub trait LedgerStore: Send {
+    fn account(&self, client: u16) -> Result<Option<ClientAccount>, StoreError>;
+
+    fn put_account(&mut self, account: ClientAccount) -> Result<(), StoreError>;
+
+    /// Every account, in client id order.
+    fn accounts(&self) -> Result<Vec<ClientAccount>, StoreError>;
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>, StoreError>;
+
+    fn put_transaction(&mut self, tx: Transaction) -> Result<(), StoreError>;
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Transaction, StoreError>> + '_>;
+
+    /// Adds to the append-only journal of events.
+    fn append_event(&mut self, event: LedgerEvent) -> Result<(), StoreError>;
+
+    /// A client's events, in the order they were appended.
+    fn events_for_client(&self, client: u16) -> Result<Vec<LedgerEvent>, StoreError>;
+
+    /// The tx id & type of the dispute, resolve or chargeback which first recorded events
+    /// under a client's action id. Kept up to date as events are appended.
+    fn action(&self, client: u16, action: u32) -> Result<Option<(u32, TxType)>, StoreError>;
+
+    /// Writes back the account & transaction changed by one input transaction & appends the
+    /// events which changed them, so the account never gets out of step with its journal.
+    /// Stores which can fail part way through must write all of it or nothing.
+    fn write_changes(
+        &mut self,
+        account: ClientAccount,
+        tx: Transaction,
+        events: &[LedgerEvent],
+    ) -> Result<(), StoreError> {
+        self.put_account(account)?;
+        self.put_transaction(tx)?;
+        for event in events {
+            self.append_event(event.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Every event, grouped by client in client id order.
+    fn events(&self) -> Box<dyn Iterator<Item = Result<LedgerEvent, StoreError>> + '_>;
+
+    /// Partitions accounts & events by `client % shards`. Transactions are shared by every
+    /// shard, as tx ids are unique across clients & a shard checks a tx id against the
+    /// transactions of other clients too.
+    fn split(self: Box<Self>, shards: usize) -> Vec<Box<dyn LedgerStore>>;
+
+    /// Joins shards produced by `split` back into one store.
+    fn merge(
+        self: Box<Self>,
+        others: Vec<Box<dyn LedgerStore>>,
+    ) -> Result<Box<dyn LedgerStore>, StoreError>;
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// How many separately locked maps the transactions of a `MemoryStore` are spread over.
+const TX_STRIPES: usize = 64;
+
+/// Key in the default tree recording that the `actions` tree covers the whole journal.
+const ACTIONS_INDEXED: &[u8] = b"actions_indexed";
+
+/// Transactions striped by tx id, so shards sharing them rarely wait on each other's lock.
+#[derive(Clone)]
+struct TxTable(Arc<[Mutex<BTreeMap<u32, Transaction>>]>);
+
+impl Default for TxTable {
+    fn default() -> Self {
+        TxTable((0..TX_STRIPES).map(|_| Mutex::default()).collect())
+    }
+}
+
+impl TxTable {
+    fn stripe(&self, tx: u32) -> &Mutex<BTreeMap<u32, Transaction>> {
+        &self.0[tx as usize % self.0.len()]
+    }
+
+    /// Every transaction, in tx id order.
+    fn all(&self) -> Vec<Transaction> {
+        let mut all: Vec<Transaction> = self
+            .0
+            .iter()
+            .flat_map(|stripe| stripe.lock().unwrap().values().cloned().collect::<Vec<_>>())
+            .collect();
+        all.sort_by_key(|tx| tx.tx);
+        all
+    }
+}
+
+/// Keeps everything in memory, so every run starts from zero.
+#[derive(Default)]
+pub struct MemoryStore {
+    accounts: BTreeMap<u16, ClientAccount>,
+    /// Shared with the other shards after a `split`.
+    transactions: TxTable,
+    events: BTreeMap<u16, Vec<LedgerEvent>>,
+    actions: BTreeMap<(u16, u32), (u32, TxType)>,
+}
+
+impl LedgerStore for MemoryStore {
+    fn account(&self, client: u16) -> Result<Option<ClientAccount>, StoreError> {
+        Ok(self.accounts.get(&client).cloned())
+    }
+
+    fn put_account(&mut self, account: ClientAccount) -> Result<(), StoreError> {
+        self.accounts.insert(account.client(), account);
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<ClientAccount>, StoreError> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>, StoreError> {
+        Ok(self
+            .transactions
+            .stripe(tx)
+            .lock()
+            .unwrap()
+            .get(&tx)
+            .cloned())
+    }
+
+    fn put_transaction(&mut self, tx: Transaction) -> Result<(), StoreError> {
+        self.transactions
+            .stripe(tx.tx)
+            .lock()
+            .unwrap()
+            .insert(tx.tx, tx);
+        Ok(())
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Transaction, StoreError>> + '_> {
+        Box::new(self.transactions.all().into_iter().map(Ok))
+    }
+
+    fn append_event(&mut self, event: LedgerEvent) -> Result<(), StoreError> {
+        if let Some(action) = event.action() {
+            self.actions
+                .entry((event.client(), action))
+                .or_insert((event.tx(), event.caused_by()));
+        }
+        self.events.entry(event.client()).or_default().push(event);
+        Ok(())
+    }
+
+    fn events_for_client(&self, client: u16) -> Result<Vec<LedgerEvent>, StoreError> {
+        Ok(self.events.get(&client).cloned().unwrap_or_default())
+    }
+
+    fn action(&self, client: u16, action: u32) -> Result<Option<(u32, TxType)>, StoreError> {
+        Ok(self.actions.get(&(client, action)).cloned())
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = Result<LedgerEvent, StoreError>> + '_> {
+        Box::new(self.events.values().flatten().cloned().map(Ok))
+    }
+
+    fn split(self: Box<Self>, shards: usize) -> Vec<Box<dyn LedgerStore>> {
+        let mut split: Vec<MemoryStore> = (0..shards)
+            .map(|_| MemoryStore {
+                transactions: self.transactions.clone(),
+                ..MemoryStore::default()
+            })
+            .collect();
+        for (client, account) in self.accounts {
+            split[shard_of(client, shards)]
+                .accounts
+                .insert(client, account);
+        }
+        for (client, events) in self.events {
+            split[shard_of(client, shards)]
+                .events
+                .insert(client, events);
+        }
+        for ((client, action), entry) in self.actions {
+            split[shard_of(client, shards)]
+                .actions
+                .insert((client, action), entry);
+        }
+        split
+            .into_iter()
+            .map(|s| Box::new(s) as Box<dyn LedgerStore>)
+            .collect()
+    }
+
+    fn merge(
+        mut self: Box<Self>,
+        others: Vec<Box<dyn LedgerStore>>,
+    ) -> Result<Box<dyn LedgerStore>, StoreError> {
+        // The shards share their transactions, so only accounts & events are joined, the
+        // action index following the events
+        for other in others {
+            for account in other.accounts()? {
+                self.put_account(account)?;
+            }
+            for event in other.events() {
+                self.append_event(event?)?;
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Keeps accounts & transactions in a sled database on disk, so balances survive across
+/// runs & the transaction history is not bound by memory. Keys are big-endian ids, so
+/// accounts are iterated in client id order. Events are keyed by client followed by a
+/// database wide increasing id, so a client's events can be read with a prefix scan.
+/// Action ids are indexed by client followed by the action id.
+#[derive(Clone)]
+pub struct SledStore {
+    db: sled::Db,
+    accounts: sled::Tree,
+    transactions: sled::Tree,
+    events: sled::Tree,
+    actions: sled::Tree,
+}
+
+/// The stored form of a `Transaction`, which unlike the input keeps its dispute state & the
+/// rejection of a deposit or withdrawal stored only to answer its retries.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct StoredTransaction {
+    pub(crate) tx_type: Option<TxType>,
+    pub(crate) client: u16,
+    pub(crate) tx: u32,
+    pub(crate) amount: Option<Decimal>,
+    #[serde(default)]
+    pub(crate) currency: Option<Currency>,
+    pub(crate) dispute_state: DisputeState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rejection: Option<LedgerError>,
+}
+
+impl From<Transaction> for StoredTransaction {
+    fn from(tx: Transaction) -> Self {
+        StoredTransaction {
+            tx_type: tx.tx_type,
+            client: tx.client,
+            tx: tx.tx,
+            amount: tx.amount,
+            currency: tx.currency,
+            dispute_state: tx.dispute_state,
+            rejection: tx.rejection,
+        }
+    }
+}
+
+impl From<StoredTransaction> for Transaction {
+    fn from(stored: StoredTransaction) -> Self {
+        Transaction {
+            tx_type: stored.tx_type,
+            client: stored.client,
+            tx: stored.tx,
+            amount: stored.amount,
+            timestamp: None,
+            currency: stored.currency,
+            action: None,
+            dispute_state: stored.dispute_state,
+            rejection: stored.rejection,
+        }
+    }
+}
+
+impl SledStore {
+    /// Opens the database at `path`, creating it if needed. Fails while another handle,
+    /// in this process or another, holds the database open.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        SledStore::from_db(sled::open(path)?)
+    }
+
+    /// A database removed once the store is dropped.
+    pub fn temporary() -> Result<Self, StoreError> {
+        SledStore::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    /// Databases written before actions were indexed get their index built from the journal
+    /// once; every write keeps it current after that.
+    fn from_db(db: sled::Db) -> Result<Self, StoreError> {
+        let store = SledStore {
+            accounts: db.open_tree("accounts")?,
+            transactions: db.open_tree("transactions")?,
+            events: db.open_tree("events")?,
+            actions: db.open_tree("actions")?,
+            db,
+        };
+        if !store.db.contains_key(ACTIONS_INDEXED)? {
+            for event in store.events() {
+                if let Some((key, value)) = action_entry(&event?)? {
+                    if !store.actions.contains_key(key)? {
+                        store.actions.insert(key, value)?;
+                    }
+                }
+            }
+            store.db.insert(ACTIONS_INDEXED, &[])?;
+        }
+        Ok(store)
+    }
+}
+
+fn action_key(client: u16, action: u32) -> [u8; 6] {
+    let mut key = [0; 6];
+    key[..2].copy_from_slice(&client.to_be_bytes());
+    key[2..].copy_from_slice(&action.to_be_bytes());
+    key
+}
+
+/// The key & value under which an event's action id is indexed, if it has one.
+fn action_entry(event: &LedgerEvent) -> Result<Option<ActionEntry>, StoreError> {
+    match event.action() {
+        Some(action) => Ok(Some((
+            action_key(event.client(), action),
+            serde_json::to_vec(&(event.tx(), event.caused_by()))?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+type ActionEntry = ([u8; 6], Vec<u8>);
+
+impl LedgerStore for SledStore {
+    fn account(&self, client: u16) -> Result<Option<ClientAccount>, StoreError> {
+        match self.accounts.get(client.to_be_bytes())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_account(&mut self, account: ClientAccount) -> Result<(), StoreError> {
+        self.accounts.insert(
+            account.client().to_be_bytes(),
+            serde_json::to_vec(&account)?,
+        )?;
+        Ok(())
+    }
+
+    fn accounts(&self) -> Result<Vec<ClientAccount>, StoreError> {
+        self.accounts
+            .iter()
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>, StoreError> {
+        match self.transactions.get(tx.to_be_bytes())? {
+            Some(bytes) => Ok(Some(
+                serde_json::from_slice::<StoredTransaction>(&bytes)?.into(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn put_transaction(&mut self, tx: Transaction) -> Result<(), StoreError> {
+        let key = tx.tx.to_be_bytes();
+        let value = serde_json::to_vec(&StoredTransaction::from(tx))?;
+        self.transactions.insert(key, value)?;
+        Ok(())
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<Transaction, StoreError>> + '_> {
+        Box::new(
+            self.transactions
+                .iter()
+                .values()
+                .map(|bytes| Ok(serde_json::from_slice::<StoredTransaction>(&bytes?)?.into())),
+        )
+    }
+
+    fn append_event(&mut self, event: LedgerEvent) -> Result<(), StoreError> {
+        let mut key = event.client().to_be_bytes().to_vec();
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        let action = action_entry(&event)?;
+        let value = serde_json::to_vec(&event)?;
+        (&self.events, &self.actions)
+            .transaction(|(events, actions)| {
+                events.insert(key.as_slice(), value.as_slice())?;
+                if let Some((action_key, action_value)) = &action {
+                    if actions.get(action_key)?.is_none() {
+                        actions.insert(action_key, action_value.as_slice())?;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError| match e {
+                TransactionError::Abort(e) | TransactionError::Storage(e) => e.into(),
+            })
+    }
+
+    /// One sled transaction over the four trees, the event ids being generated up front as
+    /// the closure may be retried.
+    fn write_changes(
+        &mut self,
+        account: ClientAccount,
+        tx: Transaction,
+        events: &[LedgerEvent],
+    ) -> Result<(), StoreError> {
+        let account_key = account.client().to_be_bytes();
+        let account_value = serde_json::to_vec(&account)?;
+        let tx_key = tx.tx.to_be_bytes();
+        let tx_value = serde_json::to_vec(&StoredTransaction::from(tx))?;
+        let mut event_entries = Vec::with_capacity(events.len());
+        for event in events {
+            let mut key = event.client().to_be_bytes().to_vec();
+            key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+            event_entries.push((key, serde_json::to_vec(event)?));
+        }
+        let mut action_entries = Vec::new();
+        for event in events {
+            action_entries.extend(action_entry(event)?);
+        }
+        (
+            &self.accounts,
+            &self.transactions,
+            &self.events,
+            &self.actions,
+        )
+            .transaction(|(accounts, transactions, events, actions)| {
+                accounts.insert(&account_key, account_value.as_slice())?;
+                transactions.insert(&tx_key, tx_value.as_slice())?;
+                for (key, value) in &event_entries {
+                    events.insert(key.as_slice(), value.as_slice())?;
+                }
+                for (key, value) in &action_entries {
+                    if actions.get(key)?.is_none() {
+                        actions.insert(key, value.as_slice())?;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError| match e {
+                TransactionError::Abort(e) | TransactionError::Storage(e) => e.into(),
+            })
+    }
+
+    fn events_for_client(&self, client: u16) -> Result<Vec<LedgerEvent>, StoreError> {
+        self.events
+            .scan_prefix(client.to_be_bytes())
+            .values()
+            .map(|bytes| Ok(serde_json::from_slice(&bytes?)?))
+            .collect()
+    }
+
+    fn action(&self, client: u16, action: u32) -> Result<Option<(u32, TxType)>, StoreError> {
+        match self.actions.get(action_key(client, action))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = Result<LedgerEvent, StoreError>> + '_> {
+        Box::new(
+            self.events
+                .iter()
+                .values()
+                .map(|bytes| Ok(serde_json::from_slice(&bytes?)?)),
+        )
+    }
+
+    /// Shards are clients of the same database, as they never touch each other's keys.
+    fn split(self: Box<Self>, shards: usize) -> Vec<Box<dyn LedgerStore>> {
+        (0..shards)
+            .map(|_| Box::new((*self).clone()) as Box<dyn LedgerStore>)
+            .collect()
+    }
+
+    fn merge(
+        self: Box<Self>,
+        _others: Vec<Box<dyn LedgerStore>>,
+    ) -> Result<Box<dyn LedgerStore>, StoreError> {
+        Ok(self)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &to.join(entry.file_name()));
+            } else {
+                std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+
+    /// sled releases its lock from background threads some time after the last handle is
+    /// dropped, so the flushed files are copied & the copy opened instead.
+    #[test]
+    fn sled_store_survives_reopening() {
+        let temp_dir = std::env::temp_dir();
+        let path = temp_dir.join(format!("bps_{}_sled_store_test", std::process::id()));
+        let copy = temp_dir.join(format!("bps_{}_sled_store_test_copy", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_dir_all(&copy);
+        {
+            let mut store = SledStore::open(&path).unwrap();
+            let deposited = LedgerEvent::Deposited {
+                client: 2,
+                tx: 7,
+                amount: Decimal::new(15, 1),
+                currency: None,
+            };
+            store
+                .put_account(ClientAccount::from_events(2, [&deposited]))
+                .unwrap();
+            store.append_event(deposited).unwrap();
+            let mut tx = Transaction::new(TxType::Deposit, 2, 7, Some(Decimal::new(15, 1)));
+            tx.dispute_state = DisputeState::Disputed;
+            store.put_transaction(tx).unwrap();
+            store.flush().unwrap();
+            copy_dir(&path, &copy);
+        }
+
+        let store = SledStore::open(&copy).unwrap();
+        let account = store.account(2).unwrap().unwrap();
+        assert_eq!(account.total(), Decimal::new(15, 1));
+        let tx = store.transaction(7).unwrap().unwrap();
+        assert_eq!(tx.client, 2);
+        assert_eq!(tx.dispute_state, DisputeState::Disputed);
+        assert!(store.transaction(8).unwrap().is_none());
+        assert_eq!(store.events_for_client(2).unwrap().len(), 1);
+        assert!(store.events_for_client(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sled_store_lists_accounts_in_client_order() {
+        let mut store = SledStore::temporary().unwrap();
+        for client in [300u16, 2, 256] {
+            store.put_account(ClientAccount::new(client)).unwrap();
+        }
+        let clients: Vec<u16> = store
+            .accounts()
+            .unwrap()
+            .iter()
+            .map(|a| a.client())
+            .collect();
+        assert_eq!(clients, vec![2, 256, 300]);
+    }
+
+    #[test]
+    fn sled_store_writes_changes_together() {
+        let mut store = SledStore::temporary().unwrap();
+        let deposited = LedgerEvent::Deposited {
+            client: 4,
+            tx: 9,
+            amount: Decimal::new(2, 0),
+            currency: None,
+        };
+        let mut account = ClientAccount::new(4);
+        account.apply(&deposited);
+        let tx = Transaction::new(TxType::Deposit, 4, 9, Some(Decimal::new(2, 0)));
+        store
+            .write_changes(account, tx, std::slice::from_ref(&deposited))
+            .unwrap();
+
+        assert_eq!(
+            store.account(4).unwrap().unwrap().total(),
+            Decimal::new(2, 0)
+        );
+        assert_eq!(store.transaction(9).unwrap().unwrap().client, 4);
+        assert_eq!(store.events_for_client(4).unwrap(), vec![deposited]);
+    }
+
+    #[test]
+    fn sled_store_indexes_actions_by_client() {
+        let mut store = SledStore::temporary().unwrap();
+        store
+            .append_event(LedgerEvent::FundsHeld {
+                client: 4,
+                tx: 9,
+                amount: Decimal::new(2, 0),
+                tx_type: TxType::Deposit,
+                currency: None,
+                action: Some(1),
+            })
+            .unwrap();
+        assert_eq!(store.action(4, 1).unwrap(), Some((9, TxType::Dispute)));
+        assert_eq!(store.action(5, 1).unwrap(), None);
+        assert_eq!(store.action(4, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn sled_store_builds_the_action_index_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("events")
+            .unwrap()
+            .insert(
+                [4u16.to_be_bytes(), [0, 0]].concat(),
+                serde_json::to_vec(&LedgerEvent::FundsHeld {
+                    client: 4,
+                    tx: 9,
+                    amount: Decimal::new(2, 0),
+                    tx_type: TxType::Deposit,
+                    currency: None,
+                    action: Some(1),
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        let store = SledStore::from_db(db.clone()).unwrap();
+        assert_eq!(store.action(4, 1).unwrap(), Some((9, TxType::Dispute)));
+        assert!(db.contains_key(ACTIONS_INDEXED).unwrap());
+
+        store.actions.clear().unwrap();
+        let store = SledStore::from_db(db).unwrap();
+        assert_eq!(store.action(4, 1).unwrap(), None);
+    }
+}
@@ -1,15 +1,33 @@
 use rust_decimal::Decimal;
+use std::fmt;
 
 pub mod account_manager;
+pub mod account_writer;
+pub mod client_account;
+pub mod double_entry;
+pub mod grpc;
+pub mod http_api;
+pub mod ledger_error;
+pub mod ledger_event;
+pub mod ledger_store;
+pub mod line_server;
+pub mod reject_sink;
+pub mod snapshot;
 pub mod tx_processor;
+pub mod tx_stream_merger;
 pub mod tx_stream_reader;
+pub mod wal;
 
 #[macro_use]
 extern crate serde_derive;
 
+pub use client_account::ClientAccount;
+pub use ledger_error::LedgerError;
+pub use ledger_event::LedgerEvent;
+
 static DECIMAL_PRECISION: u32 = 4;
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum TxType {
     #[serde(alias = "deposit")]
     Deposit,
@@ -23,16 +41,45 @@ pub enum TxType {
     Chargeback,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ClientAccount {
-    client: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum Currency {
+    #[serde(alias = "eur")]
+    EUR,
+    #[serde(alias = "gbp")]
+    GBP,
+    #[serde(alias = "usd")]
+    USD,
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum DisputeState {
+    #[default]
+    None,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// A transaction may be disputed again once resolved, but a chargeback is final.
+    pub fn can_transition_to(self, next: DisputeState) -> bool {
+        matches!(
+            (self, next),
+            (DisputeState::None, DisputeState::Disputed)
+                | (DisputeState::Resolved, DisputeState::Disputed)
+                | (DisputeState::Disputed, DisputeState::Resolved)
+                | (DisputeState::Disputed, DisputeState::ChargedBack)
+        )
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Transaction {
     #[serde(default, alias = "type")]
     tx_type: Option<TxType>,
@@ -42,6 +89,51 @@ pub struct Transaction {
     tx: u32,
     #[serde(default)]
     amount: Option<Decimal>,
+    /// Optional `timestamp` column, in any unit shared by all feeds, used to merge feeds.
     #[serde(default)]
-    is_disputed: bool,
+    timestamp: Option<u64>,
+    /// Optional `currency` column. Transactions without one share a balance of their own.
+    #[serde(default)]
+    currency: Option<Currency>,
+    /// Optional `action` column, which identifies a dispute, resolve or chargeback so a
+    /// retry of one can be told apart from a new one about the same transaction.
+    #[serde(default)]
+    action: Option<u32>,
+    #[serde(skip)]
+    dispute_state: DisputeState,
+    /// Why a deposit or withdrawal was rejected, when it is only stored so that its retries
+    /// are rejected the same way.
+    #[serde(skip)]
+    rejection: Option<LedgerError>,
+}
+
+impl Transaction {
+    pub fn new(tx_type: TxType, client: u16, tx: u32, amount: Option<Decimal>) -> Self {
+        Transaction {
+            tx_type: Some(tx_type),
+            client,
+            tx,
+            amount,
+            timestamp: None,
+            currency: None,
+            action: None,
+            dispute_state: DisputeState::None,
+            rejection: None,
+        }
+    }
+
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    pub fn with_action(mut self, action: u32) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    /// Rounds the amount of a transaction as it comes in.
+    pub(crate) fn round_amount(&mut self) {
+        self.amount = self.amount.map(|a| a.round_dp(DECIMAL_PRECISION));
+    }
 }
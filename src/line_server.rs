@@ -0,0 +1,158 @@
+use crate::account_manager::{with_ledger, AccountManager, TxOutcome};
+use crate::tx_stream_reader::{parse_line, InputFormat};
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// The columns of CSV lines when a connection does not start with its own header.
+pub const DEFAULT_HEADERS: [&str; 6] = ["type", "client", "tx", "amount", "currency", "action"];
+
+/// Accepts connections on `listener` until ctrl-c is pressed. Each connection streams
+/// records, one per line, into the shared `AccountManager`.
+pub async fn serve(
+    acc_man: Arc<Mutex<AccountManager>>,
+    listener: TcpListener,
+    format: InputFormat,
+) -> io::Result<()> {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let acc_man = acc_man.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(acc_man, stream, format).await {
+                        eprintln!("Connection from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+/// Answers every record with `ack <line>`, `ack <line> replayed` when an identical record was
+/// already applied, or `nack <line> <kind> <error>` when it could not be parsed or was
+/// rejected by the ledger, `line` counting from 1 on each connection.
+/// A CSV connection may start with a header line, which is acknowledged like a record.
+/// Blank lines are skipped without an answer.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite>(
+    acc_man: Arc<Mutex<AccountManager>>,
+    stream: S,
+    format: InputFormat,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let mut headers = csv::StringRecord::from(DEFAULT_HEADERS.to_vec());
+    let mut line = 0u64;
+    while let Some(text) = lines.next_line().await? {
+        line += 1;
+        if text.trim().is_empty() {
+            continue;
+        }
+        let reply = if line == 1 && format == InputFormat::Csv && is_header(&text) {
+            headers = text.split(',').map(str::trim).collect();
+            format!("ack {}\n", line)
+        } else {
+            apply(&acc_man, &text, format, &headers, line).await
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    writer.flush().await
+}
+
+fn is_header(text: &str) -> bool {
+    text.split(',')
+        .next()
+        .is_some_and(|first| first.trim().eq_ignore_ascii_case("type"))
+}
+
+async fn apply(
+    acc_man: &Arc<Mutex<AccountManager>>,
+    text: &str,
+    format: InputFormat,
+    headers: &csv::StringRecord,
+    line: u64,
+) -> String {
+    let result = match parse_line(text, format, headers) {
+        Ok(tx) => with_ledger(acc_man, move |acc_man| acc_man.process_tx(&tx))
+            .await
+            .map_err(|e| (e.kind(), e.to_string())),
+        Err(e) => Err((e.kind(), e.to_string())),
+    };
+    match result {
+        Ok(TxOutcome::Applied) => format!("ack {}\n", line),
+        Ok(TxOutcome::Replayed) => format!("ack {} replayed\n", line),
+        // Errors are kept to one line, so each record gets exactly one answer
+        Err((kind, error)) => format!("nack {} {} {}\n", line, kind, error.replace('\n', " ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use tokio::io::AsyncReadExt;
+
+    async fn exchange(
+        acc_man: &Arc<Mutex<AccountManager>>,
+        format: InputFormat,
+        input: &str,
+    ) -> String {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(handle_connection(acc_man.clone(), server, format));
+        client.write_all(input.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        task.await.unwrap().unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn acks_csv_lines_and_nacks_rejections() {
+        let acc_man = Arc::new(Mutex::new(AccountManager::default()));
+        let replies = exchange(
+            &acc_man,
+            InputFormat::Csv,
+            "deposit, 1, 1, 2.0\n\nwithdraw, 1, 2, 5.0\ndeposit, x, 3, 1.0\ndeposit, 1, 1, 2.0\ndeposit, 1, 1, 3.0\n",
+        )
+        .await;
+        let replies: Vec<&str> = replies.lines().collect();
+        assert_eq!(replies[0], "ack 1");
+        assert!(replies[1].starts_with("nack 3 InsufficientFunds "));
+        assert!(replies[2].starts_with("nack 4 ParseError "));
+        assert_eq!(replies[3], "ack 5 replayed");
+        assert!(replies[4].starts_with("nack 6 TransactionConflict "));
+        assert_eq!(replies.len(), 5);
+        let account = acc_man.lock().unwrap().account(1).unwrap().unwrap();
+        assert_eq!(account.available(), Decimal::new(2, 0));
+    }
+
+    #[tokio::test]
+    async fn csv_connection_may_send_its_own_header() {
+        let acc_man = Arc::new(Mutex::new(AccountManager::default()));
+        let replies = exchange(
+            &acc_man,
+            InputFormat::Csv,
+            "type,tx,client,amount\ndeposit,7,2,1.5\n",
+        )
+        .await;
+        assert_eq!(replies, "ack 1\nack 2\n");
+        assert!(acc_man.lock().unwrap().account(2).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn acks_jsonl_lines() {
+        let acc_man = Arc::new(Mutex::new(AccountManager::default()));
+        let replies = exchange(
+            &acc_man,
+            InputFormat::Jsonl,
+            "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.0\"}\n{\"type\": \"dispute\", \"client\": 1, \"tx\": 9}\n",
+        )
+        .await;
+        let replies: Vec<&str> = replies.lines().collect();
+        assert_eq!(replies[0], "ack 1");
+        assert!(replies[1].starts_with("nack 2 TransactionNotFound "));
+    }
+}
@@ -1,16 +1,205 @@
-use bank_payments_system::account_manager::AccountManager;
+use bank_payments_system::account_manager::{AccountManager, DisputePolicy};
+use bank_payments_system::account_writer::{
+    AccountWriter, CsvAccountWriter, JsonAccountWriter, TableAccountWriter,
+};
+use bank_payments_system::grpc;
+use bank_payments_system::http_api;
+use bank_payments_system::ledger_store::SledStore;
+use bank_payments_system::line_server;
+use bank_payments_system::reject_sink::RejectSink;
+use bank_payments_system::snapshot::Snapshot;
 use bank_payments_system::tx_processor::TxProcessor;
-use bank_payments_system::tx_stream_reader::TxStreamReader;
+use bank_payments_system::tx_stream_merger::MergeStrategy;
+use bank_payments_system::tx_stream_reader::{InputFormat, TxStreamReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const USAGE: &str =
+    "Expected CSV filenames, directories, globs or `-` for stdin, run with `cargo run -- transactions.csv [more.csv ...] [--format csv|jsonl] [--merge file|round-robin|timestamp] [--output csv|json|table] [--rejects rejects.jsonl] [--shards N] [--store ledger.db] [--wal ledger.wal] [--snapshot ledger.snapshot] [--snapshot-every N] [--dispute-withdrawals] [--history CLIENT | --double-entry]` or `cargo run -- serve [--listen 127.0.0.1:8080] [--tcp 127.0.0.1:9000] [--grpc 127.0.0.1:50051] [--format csv|jsonl] [--dispute-withdrawals] [--store ledger.db] [--wal ledger.wal] [--snapshot ledger.snapshot]`. `--store` cannot be combined with `--wal` or `--snapshot`";
 
 #[tokio::main]
 async fn main() {
-    let csv_path = std::env::args()
-        .nth(1)
-        .expect("Expected a CSV filename, run with `cargo run -- transactions.csv`");
+    let mut csv_paths = Vec::new();
+    let mut merge = MergeStrategy::FileOrder;
+    let mut rejects_path = None;
+    let mut store_path = None;
+    let mut wal_path = None;
+    let mut snapshot_path = None;
+    let mut snapshot_every = None;
+    let mut history = None;
+    let mut double_entry = false;
+    let mut dispute_policy = DisputePolicy::DepositsOnly;
+    let mut format = None;
+    let mut output: Box<dyn AccountWriter> = Box::new(CsvAccountWriter);
+    let mut shards = None;
+    let mut args = std::env::args().skip(1).peekable();
+    let serve = args.next_if(|arg| arg == "serve").is_some();
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut tcp_listen = None;
+    let mut grpc_listen = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rejects" => rejects_path = Some(args.next().expect(USAGE)),
+            "--listen" => listen = args.next().expect(USAGE),
+            "--tcp" => tcp_listen = Some(args.next().expect(USAGE)),
+            "--grpc" => grpc_listen = Some(args.next().expect(USAGE)),
+            "--store" => store_path = Some(args.next().expect(USAGE)),
+            "--wal" => wal_path = Some(args.next().expect(USAGE)),
+            "--snapshot" => snapshot_path = Some(args.next().expect(USAGE)),
+            "--snapshot-every" => {
+                snapshot_every = Some(
+                    args.next()
+                        .expect(USAGE)
+                        .parse()
+                        .expect("Expected a number of transactions"),
+                )
+            }
+            "--history" => {
+                history = Some(
+                    args.next()
+                        .expect(USAGE)
+                        .parse::<u16>()
+                        .expect("Expected a client id"),
+                )
+            }
+            "--format" => {
+                format = match args.next().expect(USAGE).as_str() {
+                    "csv" => Some(InputFormat::Csv),
+                    "jsonl" => Some(InputFormat::Jsonl),
+                    other => panic!("Unknown input format `{}`, expected csv or jsonl", other),
+                }
+            }
+            "--merge" => {
+                merge = match args.next().expect(USAGE).as_str() {
+                    "file" => MergeStrategy::FileOrder,
+                    "round-robin" => MergeStrategy::RoundRobin,
+                    "timestamp" => MergeStrategy::Timestamp,
+                    other => panic!(
+                        "Unknown merge strategy `{}`, expected file, round-robin or timestamp",
+                        other
+                    ),
+                }
+            }
+            "--output" => {
+                output = match args.next().expect(USAGE).as_str() {
+                    "csv" => Box::new(CsvAccountWriter),
+                    "json" => Box::new(JsonAccountWriter),
+                    "table" => Box::new(TableAccountWriter),
+                    other => panic!("Unknown output `{}`, expected csv, json or table", other),
+                }
+            }
+            "--shards" => {
+                shards = Some(
+                    args.next()
+                        .expect(USAGE)
+                        .parse()
+                        .expect("Expected a number of shards"),
+                )
+            }
+            "--double-entry" => double_entry = true,
+            "--dispute-withdrawals" => dispute_policy = DisputePolicy::DepositsAndWithdrawals,
+            _ => csv_paths.push(arg),
+        }
+    }
+    if csv_paths.is_empty() != serve {
+        panic!("{}", USAGE);
+    }
+
+    if store_path.is_some() && (wal_path.is_some() || snapshot_path.is_some()) {
+        eprintln!("--wal & --snapshot hold the whole in-memory ledger, so they cannot be combined with --store");
+        std::process::exit(2);
+    }
+    if history.is_some() && double_entry {
+        eprintln!("--history & --double-entry each replace the account balances, so only one can be given");
+        std::process::exit(2);
+    }
 
-    let tx_reader = TxStreamReader::new_from_csv(csv_path).unwrap();
-    let acc_man = AccountManager::default();
-    let mut tx_processor = TxProcessor::new(tx_reader, acc_man);
+    let mut tx_readers = Vec::new();
+    for csv_path in csv_paths {
+        tx_readers.extend(TxStreamReader::open_all(&csv_path, format).unwrap());
+    }
+    if snapshot_every.is_some() && snapshot_path.is_none() {
+        panic!("--snapshot-every needs a --snapshot path");
+    }
+    let mut acc_man = match (store_path, &snapshot_path) {
+        (Some(path), _) => {
+            AccountManager::new(Box::new(SledStore::open(path).unwrap()), dispute_policy)
+        }
+        (None, Some(path)) if Path::new(path).exists() => {
+            AccountManager::from_snapshot(Snapshot::read(path).unwrap(), dispute_policy).unwrap()
+        }
+        _ => AccountManager::with_dispute_policy(dispute_policy),
+    };
+    if let Some(path) = wal_path {
+        acc_man = acc_man.with_wal(path).unwrap();
+    }
+    if serve {
+        let listener = tokio::net::TcpListener::bind(&listen).await.unwrap();
+        eprintln!("Listening on http://{}", listener.local_addr().unwrap());
+        let acc_man = Arc::new(Mutex::new(acc_man));
+        let lines = async {
+            match &tcp_listen {
+                Some(addr) => {
+                    let listener = tokio::net::TcpListener::bind(addr).await?;
+                    eprintln!("Accepting records on tcp://{}", listener.local_addr()?);
+                    let format = format.unwrap_or(InputFormat::Csv);
+                    line_server::serve(acc_man.clone(), listener, format).await
+                }
+                None => Ok(()),
+            }
+        };
+        let rpcs = async {
+            match &grpc_listen {
+                Some(addr) => {
+                    let listener = tokio::net::TcpListener::bind(addr).await?;
+                    eprintln!("Serving gRPC on {}", listener.local_addr()?);
+                    grpc::serve(acc_man.clone(), listener)
+                        .await
+                        .map_err(std::io::Error::other)
+                }
+                None => Ok(()),
+            }
+        };
+        tokio::try_join!(http_api::serve(acc_man.clone(), listener), lines, rpcs).unwrap();
+        let mut acc_man = acc_man.lock().unwrap();
+        acc_man.flush().unwrap();
+        if let Some(path) = snapshot_path {
+            acc_man.write_snapshot(path).unwrap();
+        }
+        return;
+    }
+    let mut tx_processor = TxProcessor::from_sources(tx_readers, merge, acc_man);
+    if let Some(shards) = shards {
+        tx_processor = tx_processor.with_shards(shards);
+    }
+    if let Some(path) = snapshot_path {
+        tx_processor = tx_processor.with_snapshots(path, snapshot_every);
+    }
+    if let Some(path) = rejects_path {
+        tx_processor = tx_processor.with_reject_sink(RejectSink::from_path(path).unwrap());
+    }
     tx_processor.start().await;
-    tx_processor.print_accounts();
+    if double_entry {
+        tx_processor
+            .account_manager()
+            .trial_balance()
+            .unwrap()
+            .write(&mut std::io::stdout())
+            .unwrap();
+    } else {
+        match history {
+            Some(client) => {
+                let events = tx_processor
+                    .account_manager()
+                    .events_for_client(client)
+                    .unwrap();
+                for event in events {
+                    println!("{}", serde_json::to_string(&event).unwrap());
+                }
+            }
+            None => tx_processor
+                .write_accounts(output.as_ref(), &mut std::io::stdout())
+                .unwrap(),
+        }
+    }
 }
@@ -0,0 +1,93 @@
+use chrono::{SecondsFormat, Utc};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectFormat {
+    Csv,
+    Jsonl,
+}
+
+impl RejectFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("json") => RejectFormat::Jsonl,
+            _ => RejectFormat::Csv,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Rejection {
+    pub source: Option<String>,
+    pub line: Option<u64>,
+    pub record: String,
+    pub kind: String,
+    pub error: String,
+    pub timestamp: String,
+}
+
+impl Rejection {
+    pub fn new(
+        source: Option<&str>,
+        line: Option<u64>,
+        record: String,
+        kind: &str,
+        error: String,
+    ) -> Self {
+        Rejection {
+            source: source.map(String::from),
+            line,
+            record,
+            kind: kind.into(),
+            error,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+enum RejectWriter {
+    Csv(Box<csv::Writer<Box<dyn Write + Send>>>),
+    Jsonl(Box<dyn Write + Send>),
+}
+
+pub struct RejectSink {
+    writer: RejectWriter,
+}
+
+impl RejectSink {
+    pub fn new(writer: Box<dyn Write + Send>, format: RejectFormat) -> Self {
+        let writer = match format {
+            RejectFormat::Csv => RejectWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+            RejectFormat::Jsonl => RejectWriter::Jsonl(writer),
+        };
+        RejectSink { writer }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let format = RejectFormat::from_path(path.as_ref());
+        let file = File::create(path)?;
+        Ok(RejectSink::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    pub fn reject(&mut self, rejection: &Rejection) -> Result<(), Box<dyn Error>> {
+        match &mut self.writer {
+            RejectWriter::Csv(wtr) => wtr.serialize(rejection)?,
+            RejectWriter::Jsonl(wtr) => {
+                serde_json::to_writer(&mut *wtr, rejection)?;
+                wtr.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        match &mut self.writer {
+            RejectWriter::Csv(wtr) => wtr.flush()?,
+            RejectWriter::Jsonl(wtr) => wtr.flush()?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use crate::ledger_store::StoredTransaction;
+use crate::wal::ResumePoint;
+use crate::ClientAccount;
+use crate::LedgerEvent;
+use crate::Transaction;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Bumped whenever the layout changes, older snapshots are refused rather than misread.
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// A point-in-time copy of every account, event & stored transaction, including dispute
+/// states.
+/// `wal_seq` is the last write-ahead log entry reflected, so only the log tail after it
+/// needs replaying.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    version: u32,
+    wal_seq: u64,
+    resume: ResumePoint,
+    accounts: Vec<ClientAccount>,
+    transactions: Vec<StoredTransaction>,
+    events: Vec<LedgerEvent>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        wal_seq: u64,
+        resume: ResumePoint,
+        accounts: Vec<ClientAccount>,
+        transactions: Vec<Transaction>,
+        events: Vec<LedgerEvent>,
+    ) -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            wal_seq,
+            resume,
+            accounts,
+            transactions: transactions.into_iter().map(Into::into).collect(),
+            events,
+        }
+    }
+
+    /// Joins the snapshots of each shard.
+    pub fn merge(parts: Vec<Snapshot>) -> Snapshot {
+        let mut wal_seq = 0;
+        let mut resume = ResumePoint::default();
+        let mut accounts = BTreeMap::new();
+        let mut transactions = BTreeMap::new();
+        let mut events = Vec::new();
+        for part in parts {
+            wal_seq = wal_seq.max(part.wal_seq);
+            resume.merge(part.resume);
+            accounts.extend(part.accounts.into_iter().map(|a| (a.client(), a)));
+            transactions.extend(part.transactions.into_iter().map(|t| (t.tx, t)));
+            events.extend(part.events);
+        }
+        // Each client's events stay in order, as a client only ever lives in one shard
+        events.sort_by_key(LedgerEvent::client);
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            wal_seq,
+            resume,
+            accounts: accounts.into_values().collect(),
+            transactions: transactions.into_values().collect(),
+            events,
+        }
+    }
+
+    pub fn wal_seq(&self) -> u64 {
+        self.wal_seq
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn into_parts(
+        self,
+    ) -> (
+        u64,
+        ResumePoint,
+        Vec<ClientAccount>,
+        Vec<Transaction>,
+        Vec<LedgerEvent>,
+    ) {
+        (
+            self.wal_seq,
+            self.resume,
+            self.accounts,
+            self.transactions.into_iter().map(Into::into).collect(),
+            self.events,
+        )
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Snapshot, Box<dyn Error>> {
+        let snapshot: Snapshot = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Snapshot version {} is not supported, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            )
+            .into());
+        }
+        Ok(snapshot)
+    }
+
+    /// Writes to a temporary file which then replaces `path`, so a crash never leaves a
+    /// partly written snapshot behind.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_manager::{AccountManager, DisputePolicy};
+    use crate::TxType;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn snapshot_round_trips_dispute_state() {
+        let mut acc_man = AccountManager::default();
+        let deposit = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(5, 0)));
+        let dispute = Transaction::new(TxType::Dispute, 1, 1, None);
+        assert!(acc_man.process_tx(&deposit).is_ok());
+        assert!(acc_man.process_tx(&dispute).is_ok());
+
+        let path = std::env::temp_dir().join(format!(
+            "bps_{}_snapshot_round_trip.json",
+            std::process::id()
+        ));
+        acc_man.snapshot().unwrap().write(&path).unwrap();
+        let snapshot = Snapshot::read(&path).unwrap();
+        let mut restored =
+            AccountManager::from_snapshot(snapshot, DisputePolicy::DepositsOnly).unwrap();
+
+        let account = restored.account(1).unwrap().unwrap();
+        assert_eq!(account.held(), Decimal::new(5, 0));
+        // Only a disputed transaction can be resolved
+        let resolve = Transaction::new(TxType::Resolve, 1, 1, None);
+        assert!(restored.process_tx(&resolve).is_ok());
+        assert_eq!(
+            restored.account(1).unwrap().unwrap().available(),
+            Decimal::new(5, 0)
+        );
+        assert_eq!(restored.events_for_client(1).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn unknown_version_is_refused() {
+        let path =
+            std::env::temp_dir().join(format!("bps_{}_snapshot_version.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"version":99,"wal_seq":0,"resume":{"lines":{}},"accounts":[],"transactions":[],"events":[]}"#,
+        )
+        .unwrap();
+        assert!(Snapshot::read(&path).is_err());
+    }
+}
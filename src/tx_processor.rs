@@ -1,42 +1,387 @@
-use crate::account_manager::AccountManager;
-use crate::tx_stream_reader::TxStreamReader;
+use crate::account_manager::{shard_of, AccountManager, TxOutcome};
+use crate::account_writer::{AccountWriter, CsvAccountWriter};
+use crate::ledger_store::StoreError;
+use crate::reject_sink::{RejectSink, Rejection};
+use crate::snapshot::Snapshot;
+use crate::tx_stream_merger::{MergeStrategy, TxStreamMerger};
+use crate::tx_stream_reader::{RecordError, TxStreamReader};
+use crate::wal::{InputPosition, ResumePoint, Wal};
 use crate::Transaction;
-use crate::DECIMAL_PRECISION;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use tokio::sync::mpsc;
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Records carry the order they were read in, so their rejections can be reported in
+/// that order whichever shard they went to.
+enum ShardMessage {
+    Apply {
+        seq: u64,
+        source: Option<Arc<str>>,
+        line: Option<u64>,
+        record: String,
+        tx: Transaction,
+        reused_from: Option<Arc<str>>,
+    },
+    Reject {
+        seq: u64,
+        rejection: Rejection,
+    },
+    /// Asks the shard for a snapshot of its state, the router waits for every shard.
+    Checkpoint,
+}
+
+/// Snapshots taken by the router every `every` transactions, each compacting the
+/// write-ahead log if there is one.
+struct Checkpoints {
+    path: PathBuf,
+    every: u64,
+    parts_rx: mpsc::Receiver<Result<Snapshot, StoreError>>,
+    wal: Option<Arc<Mutex<Wal>>>,
+}
 
 pub struct TxProcessor {
-    tx_stream: TxStreamReader,
+    tx_stream: Option<TxStreamMerger>,
     acc_man: AccountManager,
+    rejects: Option<RejectSink>,
+    shards: usize,
+    snapshot_path: Option<PathBuf>,
+    snapshot_every: Option<u64>,
 }
 
 impl TxProcessor {
     pub fn new(tx_stream: TxStreamReader, acc_man: AccountManager) -> Self {
-        TxProcessor { tx_stream, acc_man }
+        TxProcessor::from_sources(vec![tx_stream], MergeStrategy::FileOrder, acc_man)
     }
 
+    /// Interleaves several feeds into one ledger, see `MergeStrategy`.
+    pub fn from_sources(
+        tx_streams: Vec<TxStreamReader>,
+        strategy: MergeStrategy,
+        acc_man: AccountManager,
+    ) -> Self {
+        TxProcessor {
+            tx_stream: Some(TxStreamMerger::new(tx_streams, strategy)),
+            acc_man,
+            rejects: None,
+            shards: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            snapshot_path: None,
+            snapshot_every: None,
+        }
+    }
+
+    /// Spreads clients across this many shards, each owning the accounts of its clients,
+    /// one per available core by default. Rejections are still reported in input order.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = shards.max(1);
+        self
+    }
+
+    /// Writes a snapshot of the ledger to `path` once the stream is processed, & also every
+    /// `every` transactions when given. Shards are paused while a snapshot is taken.
+    pub fn with_snapshots<P: Into<PathBuf>>(mut self, path: P, every: Option<u64>) -> Self {
+        self.snapshot_path = Some(path.into());
+        self.snapshot_every = every.filter(|n| *n > 0);
+        self
+    }
+
+    pub fn with_reject_sink(mut self, rejects: RejectSink) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+
+    /// Reads the stream on a blocking task & routes each transaction to the shard owning
+    /// its client, so per-client ordering is kept while shards run concurrently. Shards run
+    /// on blocking threads too, as the store & write-ahead log do file I/O.
     pub async fn start(&mut self) {
-        for buf in self.tx_stream.stream.records() {
-            match buf {
-                Ok(tx) => {
-                    match tx.deserialize::<Transaction>(None) {
-                        Ok(mut deserialized_tx) => {
-                            deserialized_tx.amount = match deserialized_tx.amount {
-                                Some(a) => Some(a.round_dp(DECIMAL_PRECISION)),
-                                None => None,
-                            };
-                            match self.acc_man.process_tx(&deserialized_tx) {
-                                Ok(_) => {}
-                                Err(e) => eprintln!("Error: {} : {:?}", e, tx),
-                            };
-                        }
-                        Err(e) => eprintln!("Error: {} : {:?}", e, tx),
-                    };
+        let tx_stream = match self.tx_stream.take() {
+            Some(s) => s,
+            None => return,
+        };
+        let acc_man = std::mem::take(&mut self.acc_man);
+        let resume = acc_man.resume_point().clone();
+
+        let (done_tx, mut done_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (settled_tx, settled_rx) = std_mpsc::channel();
+        let (parts_tx, checkpoints) = match (&self.snapshot_path, self.snapshot_every) {
+            (Some(path), Some(every)) => {
+                let (parts_tx, parts_rx) = mpsc::channel(self.shards);
+                let checkpoints = Checkpoints {
+                    path: path.clone(),
+                    every,
+                    parts_rx,
+                    wal: acc_man.wal(),
+                };
+                (Some(parts_tx), Some(checkpoints))
+            }
+            _ => (None, None),
+        };
+        let mut shard_txs = Vec::with_capacity(self.shards);
+        let mut handles = Vec::with_capacity(self.shards);
+        for shard in acc_man.split(self.shards) {
+            let (shard_tx, shard_rx) = mpsc::channel(CHANNEL_CAPACITY);
+            shard_txs.push(shard_tx);
+            let (done_tx, settled_tx, parts_tx) =
+                (done_tx.clone(), settled_tx.clone(), parts_tx.clone());
+            handles.push(tokio::task::spawn_blocking(move || {
+                run_shard(shard, shard_rx, done_tx, settled_tx, parts_tx)
+            }));
+        }
+        drop(done_tx);
+        drop(settled_tx);
+        drop(parts_tx);
+        let router = tokio::task::spawn_blocking(move || {
+            route(tx_stream, resume, shard_txs, settled_rx, checkpoints)
+        });
+
+        // Shards finish records out of order, so rejections wait for every earlier record
+        let mut finished = BTreeMap::new();
+        let mut next_seq = 0;
+        while let Some((seq, rejection)) = done_rx.recv().await {
+            finished.insert(seq, rejection);
+            while let Some(rejection) = finished.remove(&next_seq) {
+                next_seq += 1;
+                if let Some(rejection) = rejection {
+                    reject(&mut self.rejects, rejection);
                 }
-                Err(e) => eprintln!("Could not read line: {}", e),
+            }
+        }
+        router.await.expect("Router task panicked");
+        let mut shards = Vec::with_capacity(handles.len());
+        for handle in handles {
+            shards.push(handle.await.expect("Shard task panicked"));
+        }
+        self.acc_man = AccountManager::merge(shards).expect("Could not merge shards");
+        if let Err(e) = self.acc_man.flush() {
+            eprintln!("Could not flush ledger store: {}", e);
+        }
+        if let Some(path) = &self.snapshot_path {
+            if let Err(e) = self.acc_man.write_snapshot(path) {
+                eprintln!("Could not write snapshot: {}", e);
+            }
+        }
+
+        if let Some(sink) = self.rejects.as_mut() {
+            if let Err(e) = sink.flush() {
+                eprintln!("Could not flush rejects: {}", e);
             }
         }
     }
 
+    pub fn account_manager(&self) -> &AccountManager {
+        &self.acc_man
+    }
+
     pub fn print_accounts(&mut self) {
-        println!("{}", self.acc_man);
+        self.write_accounts(&CsvAccountWriter, &mut io::stdout())
+            .unwrap();
+    }
+
+    pub fn write_accounts(
+        &self,
+        writer: &dyn AccountWriter,
+        out: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        self.acc_man.write_accounts(writer, out)
+    }
+}
+
+/// Routes each record to the shard of its client. The shards share one transaction table,
+/// so a tx id used by another client is caught by the ledger as in a sequential run. A
+/// record waits until any record with the same tx id in flight on another shard has been
+/// processed, so the ledger sees uses of a tx id in input order. Rows applied by an
+/// interrupted earlier run, as recorded in the write-ahead log, are skipped.
+fn route(
+    tx_stream: TxStreamMerger,
+    resume: ResumePoint,
+    shard_txs: Vec<mpsc::Sender<ShardMessage>>,
+    settled_rx: std_mpsc::Receiver<u32>,
+    mut checkpoints: Option<Checkpoints>,
+) {
+    let shards = shard_txs.len();
+    // The shard & number of records in flight for each tx id
+    let mut in_flight: HashMap<u32, (usize, usize)> = HashMap::new();
+    let mut seq = 0u64;
+    let mut routed = 0u64;
+    for record in tx_stream {
+        let tx = match record.tx {
+            Ok(tx) => tx,
+            Err(e) => {
+                let rejection = Rejection::new(
+                    record.source.as_deref(),
+                    record.line,
+                    record.record,
+                    e.kind(),
+                    e.to_string(),
+                );
+                if shard_txs[0]
+                    .blocking_send(ShardMessage::Reject { seq, rejection })
+                    .is_err()
+                {
+                    return;
+                }
+                seq += 1;
+                continue;
+            }
+        };
+        if resume.is_applied(record.source.as_deref(), tx.client, record.line) {
+            continue;
+        }
+        let shard = shard_of(tx.client, shards);
+        while let Ok(id) = settled_rx.try_recv() {
+            settle(&mut in_flight, id);
+        }
+        while in_flight.get(&tx.tx).is_some_and(|(s, _)| *s != shard) {
+            match settled_rx.recv() {
+                Ok(id) => settle(&mut in_flight, id),
+                Err(_) => return,
+            }
+        }
+        in_flight.entry(tx.tx).or_insert((shard, 0)).1 += 1;
+        let message = ShardMessage::Apply {
+            seq,
+            source: record.source,
+            line: record.line,
+            record: record.record,
+            tx,
+            reused_from: record.reused_from,
+        };
+        if shard_txs[shard].blocking_send(message).is_err() {
+            return;
+        }
+        seq += 1;
+        routed += 1;
+        if let Some(checkpoints) = checkpoints.as_mut() {
+            if routed.is_multiple_of(checkpoints.every) && !checkpoints.write(&shard_txs) {
+                return;
+            }
+        }
+    }
+}
+
+/// Marks one record using the tx id `id` as processed by its shard.
+fn settle(in_flight: &mut HashMap<u32, (usize, usize)>, id: u32) {
+    if let Some((_, count)) = in_flight.get_mut(&id) {
+        *count -= 1;
+        if *count == 0 {
+            in_flight.remove(&id);
+        }
+    }
+}
+
+impl Checkpoints {
+    /// Collects a snapshot from every shard & writes them as one. Returns false once the
+    /// shards have gone away.
+    fn write(&mut self, shard_txs: &[mpsc::Sender<ShardMessage>]) -> bool {
+        for shard_tx in shard_txs {
+            if shard_tx.blocking_send(ShardMessage::Checkpoint).is_err() {
+                return false;
+            }
+        }
+        let mut parts = Vec::with_capacity(shard_txs.len());
+        let mut failed = None;
+        for _ in shard_txs {
+            match self.parts_rx.blocking_recv() {
+                Some(Ok(part)) => parts.push(part),
+                Some(Err(e)) => failed = Some(e as Box<dyn Error>),
+                None => return false,
+            }
+        }
+        let written = match failed {
+            Some(e) => Err(e),
+            None => self.write_merged(parts),
+        };
+        if let Err(e) = written {
+            eprintln!("Could not write snapshot: {}", e);
+        }
+        true
+    }
+
+    fn write_merged(&self, parts: Vec<Snapshot>) -> Result<(), Box<dyn Error>> {
+        let snapshot = Snapshot::merge(parts);
+        snapshot.write(&self.path)?;
+        match &self.wal {
+            Some(wal) => wal.lock().unwrap().compact(snapshot.wal_seq()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reports every record it was sent as done, with its rejection if any, & the tx id of
+/// every applied record as settled.
+fn run_shard(
+    mut acc_man: AccountManager,
+    mut shard_rx: mpsc::Receiver<ShardMessage>,
+    done_tx: mpsc::Sender<(u64, Option<Rejection>)>,
+    settled_tx: std_mpsc::Sender<u32>,
+    parts_tx: Option<mpsc::Sender<Result<Snapshot, StoreError>>>,
+) -> AccountManager {
+    while let Some(message) = shard_rx.blocking_recv() {
+        let done = match message {
+            ShardMessage::Apply {
+                seq,
+                source,
+                line,
+                record,
+                tx,
+                reused_from,
+            } => {
+                let processed = acc_man.process_tx_at(
+                    &tx,
+                    InputPosition {
+                        source: source.as_deref().map(String::from),
+                        line,
+                    },
+                );
+                let _ = settled_tx.send(tx.tx);
+                let error = match (processed, reused_from) {
+                    // A retry within a feed is expected, the same record in two feeds is not
+                    (Ok(TxOutcome::Replayed), Some(feed)) => {
+                        let e = RecordError::Duplicate(format!(
+                            "Duplicate Transaction, tx {} was already applied from {}",
+                            tx.tx, feed
+                        ));
+                        Some((e.kind(), e.to_string()))
+                    }
+                    (Ok(_), _) => None,
+                    (Err(e), _) => Some((e.kind(), e.to_string())),
+                };
+                let rejection = error.map(|(kind, error)| {
+                    Rejection::new(source.as_deref(), line, record, kind, error)
+                });
+                (seq, rejection)
+            }
+            ShardMessage::Reject { seq, rejection } => (seq, Some(rejection)),
+            ShardMessage::Checkpoint => match &parts_tx {
+                Some(parts_tx) if parts_tx.blocking_send(acc_man.snapshot()).is_ok() => continue,
+                _ => break,
+            },
+        };
+        if done_tx.blocking_send(done).is_err() {
+            break;
+        }
+    }
+    acc_man
+}
+
+fn reject(rejects: &mut Option<RejectSink>, rejection: Rejection) {
+    match rejects.as_mut() {
+        Some(sink) => {
+            if let Err(e) = sink.reject(&rejection) {
+                eprintln!("Could not write reject: {} : {:?}", e, rejection);
+            }
+        }
+        None => eprintln!(
+            "Error: line={} kind={} : {} : {}",
+            rejection.line.unwrap_or_default(),
+            rejection.kind,
+            rejection.error,
+            rejection.record
+        ),
     }
 }
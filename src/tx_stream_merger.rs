@@ -0,0 +1,193 @@
+use crate::tx_stream_reader::{TxRecord, TxStreamReader};
+use crate::TxType;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MergeStrategy {
+    /// Every record of the first feed, then the second & so on.
+    #[default]
+    FileOrder,
+    /// One record from each feed in turn.
+    RoundRobin,
+    /// The feed whose next record has the earliest `timestamp`, assuming each feed is sorted.
+    /// Records without a timestamp, including those which could not be parsed, sort before
+    /// every timestamp, so they are taken as soon as they are at the head of a feed rather
+    /// than held back until the other feeds run dry.
+    Timestamp,
+}
+
+/// Interleaves several feeds into one stream of records.
+/// A deposit or withdrawal reusing a tx id first read from another feed is marked with
+/// `TxRecord::reused_from` & still left to the ledger, which rejects it as a
+/// `TransactionConflict` unless it is identical. An identical one is then reported as a
+/// `RecordError::Duplicate` rather than silently replayed.
+pub struct TxStreamMerger {
+    feeds: Vec<Peekable<TxStreamReader>>,
+    names: Vec<Arc<str>>,
+    strategy: MergeStrategy,
+    current: usize,
+    /// The feed which first used each deposit & withdrawal tx id.
+    first_feed: HashMap<u32, usize>,
+}
+
+impl TxStreamMerger {
+    pub fn new(feeds: Vec<TxStreamReader>, strategy: MergeStrategy) -> Self {
+        let names = feeds
+            .iter()
+            .enumerate()
+            .map(|(i, f)| match f.name() {
+                Some(name) => Arc::from(name),
+                None => Arc::from(format!("feed {}", i)),
+            })
+            .collect();
+        TxStreamMerger {
+            feeds: feeds.into_iter().map(Iterator::peekable).collect(),
+            names,
+            strategy,
+            current: 0,
+            first_feed: HashMap::new(),
+        }
+    }
+
+    fn mark_reuse(&mut self, feed: usize, record: &mut TxRecord) {
+        let tx = match &record.tx {
+            Ok(tx) if matches!(tx.tx_type, Some(TxType::Deposit) | Some(TxType::Withdraw)) => tx.tx,
+            _ => return,
+        };
+        let first = *self.first_feed.entry(tx).or_insert(feed);
+        if first != feed {
+            record.reused_from = Some(Arc::clone(&self.names[first]));
+        }
+    }
+
+    fn next_feed(&mut self) -> Option<usize> {
+        match self.strategy {
+            MergeStrategy::FileOrder => {
+                while self.current < self.feeds.len() {
+                    if self.feeds[self.current].peek().is_some() {
+                        return Some(self.current);
+                    }
+                    self.current += 1;
+                }
+                None
+            }
+            MergeStrategy::RoundRobin => {
+                for _ in 0..self.feeds.len() {
+                    let i = self.current;
+                    self.current = (self.current + 1) % self.feeds.len();
+                    if self.feeds[i].peek().is_some() {
+                        return Some(i);
+                    }
+                }
+                None
+            }
+            // `None` orders before any `Some`, so records without a timestamp go first
+            MergeStrategy::Timestamp => self
+                .feeds
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, f)| f.peek().map(|r| (timestamp(r), i)))
+                .min()
+                .map(|(_, i)| i),
+        }
+    }
+}
+
+fn timestamp(record: &TxRecord) -> Option<u64> {
+    record.tx.as_ref().ok().and_then(|tx| tx.timestamp)
+}
+
+impl Iterator for TxStreamMerger {
+    type Item = TxRecord;
+
+    fn next(&mut self) -> Option<TxRecord> {
+        let feed = self.next_feed()?;
+        let mut record = self.feeds[feed].next()?;
+        if self.feeds.len() > 1 {
+            self.mark_reuse(feed, &mut record);
+        }
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_stream_reader::InputFormat;
+    use std::io::Cursor;
+
+    fn feed(name: &str, csv: &str) -> TxStreamReader {
+        TxStreamReader::from_reader(Cursor::new(csv.as_bytes().to_vec()), InputFormat::Csv)
+            .with_name(name)
+    }
+
+    fn feeds() -> Vec<TxStreamReader> {
+        vec![
+            feed(
+                "a",
+                "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,10\ndeposit,1,2,1.0,30\n",
+            ),
+            feed(
+                "b",
+                "type,client,tx,amount,timestamp\ndeposit,2,3,1.0,20\ndeposit,2,4,1.0,40\n",
+            ),
+        ]
+    }
+
+    fn tx_ids(merger: TxStreamMerger) -> Vec<u32> {
+        merger.map(|r| r.tx.unwrap().tx).collect()
+    }
+
+    #[test]
+    fn merges_in_file_order() {
+        let merger = TxStreamMerger::new(feeds(), MergeStrategy::FileOrder);
+        assert_eq!(tx_ids(merger), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn merges_round_robin() {
+        let merger = TxStreamMerger::new(feeds(), MergeStrategy::RoundRobin);
+        assert_eq!(tx_ids(merger), vec![1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn merges_by_timestamp() {
+        let mut feeds = feeds();
+        feeds.reverse();
+        let merger = TxStreamMerger::new(feeds, MergeStrategy::Timestamp);
+        assert_eq!(tx_ids(merger), vec![1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn merges_records_without_timestamp_as_soon_as_reached() {
+        let feeds = vec![
+            feed(
+                "a",
+                "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,10\ndeposit,1,2,1.0,30\n",
+            ),
+            feed(
+                "b",
+                "type,client,tx,amount,timestamp\ndeposit,2,3,1.0,20\ndeposit,2,4,1.0,\n",
+            ),
+        ];
+        let merger = TxStreamMerger::new(feeds, MergeStrategy::Timestamp);
+        assert_eq!(tx_ids(merger), vec![1, 3, 4, 2]);
+    }
+
+    #[test]
+    fn marks_tx_ids_reused_across_feeds() {
+        let feeds = vec![
+            feed("a", "type,client,tx,amount\ndeposit,1,1,1.0\n"),
+            feed(
+                "b",
+                "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,1,2.0\ndispute,1,1,\ndeposit,2,2,1.0\n",
+            ),
+        ];
+        let records: Vec<TxRecord> = TxStreamMerger::new(feeds, MergeStrategy::FileOrder).collect();
+        assert!(records.iter().all(|r| r.tx.is_ok()));
+        let reused: Vec<Option<&str>> = records.iter().map(|r| r.reused_from.as_deref()).collect();
+        assert_eq!(reused, vec![None, Some("a"), Some("a"), None, None]);
+    }
+}
@@ -1,26 +1,443 @@
+use crate::Transaction;
+use flate2::read::MultiGzDecoder;
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Some(Compression::Gzip),
+            Some("zst") => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Picks the format from the extension, looking past any `.gz` or `.zst` suffix.
+    pub fn from_path(path: &Path) -> Self {
+        let path = match Compression::from_path(path) {
+            Some(_) => path.with_extension(""),
+            None => path.to_path_buf(),
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordError {
+    Read(String),
+    Parse(String),
+    /// A tx id already applied from another feed.
+    Duplicate(String),
+}
+
+impl RecordError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RecordError::Read(_) => "ReadError",
+            RecordError::Parse(_) => "ParseError",
+            RecordError::Duplicate(_) => "DuplicateAcrossSources",
+        }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Read(e) | RecordError::Parse(e) | RecordError::Duplicate(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// A single input record along with where it came from.
+#[derive(Debug)]
+pub struct TxRecord {
+    pub source: Option<Arc<str>>,
+    pub line: Option<u64>,
+    pub record: String,
+    pub tx: Result<Transaction, RecordError>,
+    /// The feed which first used the tx id of this deposit or withdrawal, when it is another
+    /// feed than this record's. Set by a `TxStreamMerger`.
+    pub reused_from: Option<Arc<str>>,
+}
+
+/// Keeps a copy of the bytes the CSV reader consumes, so a record can be reported exactly as
+/// it was written, quoting & whitespace included.
+struct Tee {
+    inner: Box<dyn Read + Send>,
+    seen: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Tee {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.seen.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+enum TxSource {
+    Csv {
+        reader: csv::Reader<Tee>,
+        /// Columns are matched by header, so optional ones may come in any order.
+        headers: Option<csv::StringRecord>,
+        /// Bytes read but not yet handed out as a record, starting at byte offset `start`.
+        seen: Arc<Mutex<Vec<u8>>>,
+        start: u64,
+    },
+    Jsonl {
+        lines: BufReader<Box<dyn Read + Send>>,
+        line: u64,
+    },
+}
 
 pub struct TxStreamReader {
-    pub stream: csv::Reader<BufReader<File>>,
+    name: Option<Arc<str>>,
+    source: TxSource,
 }
 
 impl TxStreamReader {
+    /// Opens a CSV file, or reads from stdin when the path is `-`.
+    /// Gzip & zstd input is decompressed while streaming.
     pub fn new_from_csv(csv_path: String) -> Result<Self, Box<dyn Error>> {
-        let file = File::open(csv_path)?;
+        TxStreamReader::open(csv_path, InputFormat::Csv)
+    }
+
+    /// Opens a JSON Lines file, or reads from stdin when the path is `-`.
+    pub fn new_from_jsonl(jsonl_path: String) -> Result<Self, Box<dyn Error>> {
+        TxStreamReader::open(jsonl_path, InputFormat::Jsonl)
+    }
+
+    /// Opens a file in the format given by its extension.
+    pub fn new_from_path(path: String) -> Result<Self, Box<dyn Error>> {
+        let format = InputFormat::from_path(Path::new(&path));
+        TxStreamReader::open(path, format)
+    }
+
+    pub fn open(path: String, format: InputFormat) -> Result<Self, Box<dyn Error>> {
+        if path == "-" {
+            let reader = decompress(BufReader::new(io::stdin()), None)?;
+            return Ok(TxStreamReader::from_reader(reader, format));
+        }
+        let compression = Compression::from_path(Path::new(&path));
+        let file = File::open(&path)?;
         let buffered_file_reader = BufReader::new(file);
-        let tsr: csv::Reader<BufReader<File>> = TxStreamReader::csv_reader(buffered_file_reader);
-        Ok(TxStreamReader { stream: tsr })
-    }
-    fn csv_reader(reader: BufReader<File>) -> csv::Reader<BufReader<File>> {
-        let csv_reader = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .has_headers(true)
-            .delimiter(b',')
-            .flexible(true)
-            .double_quote(false)
-            .from_reader(reader);
-        csv_reader
+        let reader = decompress(buffered_file_reader, compression)?;
+        Ok(TxStreamReader::from_reader(reader, format).with_name(&path))
+    }
+
+    /// Opens every file in a directory, or matching a glob such as `feeds/*.csv`, in name
+    /// order. Anything else is opened as a single path. The format is picked per file
+    /// from its extension unless given.
+    pub fn open_all(
+        pattern: &str,
+        format: Option<InputFormat>,
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let path = Path::new(pattern);
+        let mut paths: Vec<PathBuf> = if path.is_dir() {
+            let mut paths = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                let entry_path = entry?.path();
+                if entry_path.is_file() {
+                    paths.push(entry_path);
+                }
+            }
+            paths
+        } else if pattern.contains(['*', '?', '[']) {
+            glob::glob(pattern)?.collect::<Result<_, _>>()?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|p| {
+                let p = p.to_string_lossy().into_owned();
+                match format {
+                    Some(format) => TxStreamReader::open(p, format),
+                    None => TxStreamReader::new_from_path(p),
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, format: InputFormat) -> Self {
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        let source = match format {
+            InputFormat::Csv => {
+                let seen = Arc::new(Mutex::new(Vec::new()));
+                let mut reader = TxStreamReader::csv_reader(Tee {
+                    inner: reader,
+                    seen: Arc::clone(&seen),
+                });
+                let headers = reader.headers().ok().cloned();
+                let start = reader.position().byte();
+                seen.lock().unwrap().drain(..start as usize);
+                TxSource::Csv {
+                    reader,
+                    headers,
+                    seen,
+                    start,
+                }
+            }
+            InputFormat::Jsonl => TxSource::Jsonl {
+                lines: BufReader::new(reader),
+                line: 0,
+            },
+        };
+        TxStreamReader { name: None, source }
+    }
+
+    /// Names the feed, the name is carried by each record & reported with rejections.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn csv_reader(reader: Tee) -> csv::Reader<Tee> {
+        csv_builder().has_headers(true).from_reader(reader)
+    }
+}
+
+fn csv_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .trim(csv::Trim::All)
+        .delimiter(b',')
+        .flexible(true)
+        .double_quote(false);
+    builder
+}
+
+/// Parses a single record, such as a line received over a socket. CSV columns are matched
+/// by `headers`.
+pub fn parse_line(
+    line: &str,
+    format: InputFormat,
+    headers: &csv::StringRecord,
+) -> Result<Transaction, RecordError> {
+    let mut tx = match format {
+        InputFormat::Csv => {
+            let mut row = csv::StringRecord::new();
+            csv_builder()
+                .has_headers(false)
+                .from_reader(line.as_bytes())
+                .read_record(&mut row)
+                .map_err(|e| RecordError::Read(e.to_string()))?;
+            row.deserialize::<Transaction>(Some(headers))
+                .map_err(|e| RecordError::Parse(e.to_string()))?
+        }
+        InputFormat::Jsonl => serde_json::from_str::<Transaction>(line)
+            .map_err(|e| RecordError::Parse(e.to_string()))?,
+    };
+    tx.round_amount();
+    Ok(tx)
+}
+
+/// Takes the bytes between `start` & the reader's position out of `seen`, as the raw text of
+/// the record just read without its line ending.
+fn take_raw(reader: &csv::Reader<Tee>, seen: &Mutex<Vec<u8>>, start: &mut u64) -> String {
+    let end = reader.position().byte();
+    let bytes: Vec<u8> = seen
+        .lock()
+        .unwrap()
+        .drain(..(end - *start) as usize)
+        .collect();
+    *start = end;
+    String::from_utf8_lossy(&bytes)
+        .trim_matches(['\r', '\n'])
+        .to_string()
+}
+
+impl Iterator for TxStreamReader {
+    type Item = TxRecord;
+
+    fn next(&mut self) -> Option<TxRecord> {
+        let mut record = match &mut self.source {
+            TxSource::Csv {
+                reader,
+                headers,
+                seen,
+                start,
+            } => {
+                let mut row = csv::StringRecord::new();
+                match reader.read_record(&mut row) {
+                    Ok(false) => return None,
+                    Ok(true) => TxRecord {
+                        source: self.name.clone(),
+                        reused_from: None,
+                        line: row.position().map(|p| p.line()),
+                        record: take_raw(reader, seen, start),
+                        tx: row
+                            .deserialize::<Transaction>(headers.as_ref())
+                            .map_err(|e| RecordError::Parse(e.to_string())),
+                    },
+                    Err(e) => TxRecord {
+                        source: self.name.clone(),
+                        reused_from: None,
+                        line: e.position().map(|p| p.line()),
+                        record: take_raw(reader, seen, start),
+                        tx: Err(RecordError::Read(e.to_string())),
+                    },
+                }
+            }
+            TxSource::Jsonl { lines, line } => loop {
+                *line += 1;
+                let mut bytes = Vec::new();
+                match lines.read_until(b'\n', &mut bytes) {
+                    Ok(0) => return None,
+                    Ok(_) => {}
+                    Err(e) => {
+                        break TxRecord {
+                            source: self.name.clone(),
+                            reused_from: None,
+                            line: Some(*line),
+                            record: String::new(),
+                            tx: Err(RecordError::Read(e.to_string())),
+                        }
+                    }
+                }
+                let text = match String::from_utf8(bytes) {
+                    Ok(text) => text.trim_end_matches(['\r', '\n']).to_string(),
+                    Err(e) => {
+                        break TxRecord {
+                            source: self.name.clone(),
+                            reused_from: None,
+                            line: Some(*line),
+                            record: String::from_utf8_lossy(e.as_bytes())
+                                .trim_end_matches(['\r', '\n'])
+                                .to_string(),
+                            tx: Err(RecordError::Read(e.utf8_error().to_string())),
+                        }
+                    }
+                };
+                if text.trim().is_empty() {
+                    continue;
+                }
+                break TxRecord {
+                    source: self.name.clone(),
+                    reused_from: None,
+                    line: Some(*line),
+                    tx: serde_json::from_str::<Transaction>(&text)
+                        .map_err(|e| RecordError::Parse(e.to_string())),
+                    record: text,
+                };
+            },
+        };
+        if let Ok(tx) = record.tx.as_mut() {
+            tx.round_amount();
+        }
+        Some(record)
+    }
+}
+
+/// Wraps the reader in a decoder, sniffing the magic bytes when the compression is not known.
+fn decompress<R: BufRead + Send + 'static>(
+    mut reader: R,
+    compression: Option<Compression>,
+) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+    let (compression, magic) = match compression {
+        Some(c) => (c, Vec::new()),
+        None => {
+            let magic = read_magic(&mut reader)?;
+            (Compression::from_magic(&magic), magic)
+        }
+    };
+    // The sniffed bytes were consumed, so they are put back in front of the rest
+    let reader = io::Cursor::new(magic).chain(reader);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(BufReader::new(
+            reader,
+        ))?),
+    })
+}
+
+/// Reads as many bytes as the longest magic number, or fewer at EOF. A single read from a pipe
+/// or stdin may return less than that even when more is coming.
+fn read_magic<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    reader
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    /// Hands out one byte per read, as a pipe may.
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn sniffs_compression_from_short_reads() {
+        let csv = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(csv).unwrap();
+        let inputs = [
+            gz.finish().unwrap(),
+            zstd::encode_all(&csv[..], 0).unwrap(),
+            csv.to_vec(),
+        ];
+        for input in inputs {
+            let reader = BufReader::with_capacity(1, Trickle(io::Cursor::new(input)));
+            let mut out = Vec::new();
+            decompress(reader, None)
+                .unwrap()
+                .read_to_end(&mut out)
+                .unwrap();
+            assert_eq!(out, csv);
+        }
     }
 }
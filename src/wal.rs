@@ -0,0 +1,282 @@
+use crate::Transaction;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Where a transaction was read from. Unnamed sources, such as stdin, have no `source`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct InputPosition {
+    pub source: Option<String>,
+    pub line: Option<u64>,
+}
+
+/// One accepted transaction, logged before it takes effect, or a deposit or withdrawal
+/// rejected because of the ledger's state, which replaying rejects again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WalEntry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub position: InputPosition,
+    pub tx: Transaction,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rejected: bool,
+}
+
+/// The last applied input line of each client, per source.
+/// A client's transactions are applied in input order, even when sharded, so any row at or
+/// before its client's line was already applied & can be skipped when resuming.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ResumePoint {
+    lines: BTreeMap<String, BTreeMap<u16, u64>>,
+}
+
+impl ResumePoint {
+    pub fn record(&mut self, position: &InputPosition, client: u16) {
+        if let Some(line) = position.line {
+            let source = position.source.clone().unwrap_or_default();
+            let last = self
+                .lines
+                .entry(source)
+                .or_default()
+                .entry(client)
+                .or_insert(line);
+            *last = (*last).max(line);
+        }
+    }
+
+    pub fn is_applied(&self, source: Option<&str>, client: u16, line: Option<u64>) -> bool {
+        match (line, self.lines.get(source.unwrap_or_default())) {
+            (Some(line), Some(clients)) => clients.get(&client).is_some_and(|l| line <= *l),
+            _ => false,
+        }
+    }
+
+    /// Keeps the furthest line of both.
+    pub fn merge(&mut self, other: ResumePoint) {
+        for (source, clients) in other.lines {
+            let lines = self.lines.entry(source).or_default();
+            for (client, line) in clients {
+                let last = lines.entry(client).or_insert(line);
+                *last = (*last).max(line);
+            }
+        }
+    }
+}
+
+/// An append-only log of accepted transactions, one JSON object per line.
+/// Each entry is written through to the file before the transaction takes effect, so it
+/// survives the process dying; `sync` also forces it to disk. Once a snapshot covers the
+/// log, `compact` drops the entries it holds.
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+    next_seq: u64,
+}
+
+impl Wal {
+    /// Opens or creates the log, handing each entry already in it to `replay` as it is read,
+    /// so the log is never held in memory. A torn final line, left by a crash part way
+    /// through a write, is dropped from the file.
+    pub fn open<P, F>(path: P, mut replay: F) -> Result<Wal, Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+        F: FnMut(WalEntry) -> Result<(), Box<dyn Error>>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut last_seq = 0;
+        let mut valid_len = 0u64;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                // Torn write, nothing after it can have been applied
+                break;
+            }
+            let entry: WalEntry = serde_json::from_str(&line).map_err(|e| {
+                format!(
+                    "Corrupt write-ahead log entry after seq {}: {}",
+                    last_seq, e
+                )
+            })?;
+            last_seq = entry.seq;
+            replay(entry)?;
+            valid_len += read as u64;
+        }
+        drop(reader);
+        if file.seek(SeekFrom::End(0))? != valid_len {
+            file.set_len(valid_len)?;
+        }
+        Ok(Wal {
+            file,
+            path,
+            next_seq: last_seq + 1,
+        })
+    }
+
+    /// Drops the entries before `seq` once a snapshot covers every entry up to it. The entry
+    /// `seq` itself is kept, so numbering carries on from it after a restart. The remaining
+    /// entries are copied to a new file which then replaces the log.
+    pub fn compact(&mut self, seq: u64) -> Result<(), Box<dyn Error>> {
+        let tmp_path = self.path.with_extension("compacting");
+        let mut tmp = File::create(&tmp_path)?;
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = line?;
+            let entry: WalEntry = serde_json::from_str(&line)?;
+            if entry.seq >= seq {
+                writeln!(tmp, "{}", line)?;
+            }
+        }
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn append(&mut self, position: &InputPosition, tx: &Transaction) -> io::Result<u64> {
+        self.write_entry(position, tx, false)
+    }
+
+    pub fn append_rejected(
+        &mut self,
+        position: &InputPosition,
+        tx: &Transaction,
+    ) -> io::Result<u64> {
+        self.write_entry(position, tx, true)
+    }
+
+    fn write_entry(
+        &mut self,
+        position: &InputPosition,
+        tx: &Transaction,
+        rejected: bool,
+    ) -> io::Result<u64> {
+        let entry = WalEntry {
+            seq: self.next_seq,
+            position: position.clone(),
+            tx: tx.clone(),
+            rejected,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.next_seq += 1;
+        Ok(entry.seq)
+    }
+
+    /// The sequence number of the last entry, 0 when the log is empty.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TxType;
+    use rust_decimal::Decimal;
+
+    fn open(path: &Path) -> (Wal, Vec<WalEntry>) {
+        let mut entries = Vec::new();
+        let wal = Wal::open(path, |entry| {
+            entries.push(entry);
+            Ok(())
+        })
+        .unwrap();
+        (wal, entries)
+    }
+
+    fn position(line: u64) -> InputPosition {
+        InputPosition {
+            source: Some("in.csv".into()),
+            line: Some(line),
+        }
+    }
+
+    #[test]
+    fn reopening_returns_appended_entries() {
+        let path =
+            std::env::temp_dir().join(format!("bps_{}_wal_reopen.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut wal, entries) = open(&path);
+        assert!(entries.is_empty());
+        let tx = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(15, 1)));
+        assert_eq!(wal.append(&position(2), &tx).unwrap(), 1);
+        assert_eq!(wal.append(&position(3), &tx).unwrap(), 2);
+        drop(wal);
+
+        let (wal, entries) = open(&path);
+        assert_eq!(wal.last_seq(), 2);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].position, position(3));
+        assert_eq!(entries[1].tx.amount, Some(Decimal::new(15, 1)));
+    }
+
+    #[test]
+    fn torn_last_line_is_dropped() {
+        let path = std::env::temp_dir().join(format!("bps_{}_wal_torn.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut wal, _) = open(&path);
+        let tx = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(1, 0)));
+        wal.append(&position(2), &tx).unwrap();
+        drop(wal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"seq\":2,\"sou").unwrap();
+        drop(file);
+
+        let (mut wal, entries) = open(&path);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(wal.append(&position(3), &tx).unwrap(), 2);
+        drop(wal);
+        let (_, entries) = open(&path);
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn compacting_keeps_numbering() {
+        let path =
+            std::env::temp_dir().join(format!("bps_{}_wal_compact.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut wal, _) = open(&path);
+        let tx = Transaction::new(TxType::Deposit, 1, 1, Some(Decimal::new(1, 0)));
+        for line in 1..=4 {
+            wal.append(&position(line), &tx).unwrap();
+        }
+        wal.compact(3).unwrap();
+        assert_eq!(wal.append(&position(5), &tx).unwrap(), 5);
+        drop(wal);
+
+        let (wal, entries) = open(&path);
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![3, 4, 5]);
+        assert_eq!(wal.last_seq(), 5);
+    }
+
+    #[test]
+    fn resume_point_keeps_last_line_per_client() {
+        let mut resume = ResumePoint::default();
+        resume.record(&position(5), 1);
+        resume.record(&position(3), 1);
+        resume.record(&position(4), 2);
+        assert!(resume.is_applied(Some("in.csv"), 1, Some(5)));
+        assert!(!resume.is_applied(Some("in.csv"), 1, Some(6)));
+        assert!(resume.is_applied(Some("in.csv"), 2, Some(4)));
+        assert!(!resume.is_applied(Some("other.csv"), 1, Some(1)));
+        assert!(!resume.is_applied(Some("in.csv"), 1, None));
+    }
+}
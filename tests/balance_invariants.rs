@@ -0,0 +1,100 @@
+use bank_payments_system::account_manager::{AccountManager, DisputePolicy, TxOutcome};
+use bank_payments_system::{ClientAccount, Transaction, TxType};
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+fn tx_strategy() -> impl Strategy<Value = (TxType, u16, u32, Option<Decimal>)> {
+    let tx_type = prop_oneof![
+        Just(TxType::Deposit),
+        Just(TxType::Withdraw),
+        Just(TxType::Dispute),
+        Just(TxType::Resolve),
+        Just(TxType::Chargeback),
+    ];
+    (
+        tx_type,
+        1..4u16,
+        1..16u32,
+        proptest::option::weighted(0.9, -100i64..10_000),
+    )
+        .prop_map(|(tx_type, client, tx, amount)| {
+            (tx_type, client, tx, amount.map(|a| Decimal::new(a, 2)))
+        })
+}
+
+#[derive(Default)]
+struct Expected {
+    total: Decimal,
+    held: Decimal,
+}
+
+proptest! {
+    #[test]
+    fn totals_are_conserved(
+        txs in proptest::collection::vec(tx_strategy(), 0..200),
+        dispute_withdrawals in any::<bool>(),
+    ) {
+        let policy = if dispute_withdrawals {
+            DisputePolicy::DepositsAndWithdrawals
+        } else {
+            DisputePolicy::DepositsOnly
+        };
+        let mut acc_man = AccountManager::with_dispute_policy(policy);
+        let mut expected: HashMap<u16, Expected> = HashMap::new();
+        let mut applied: HashMap<u32, (TxType, Decimal)> = HashMap::new();
+        let mut net_inflow = Decimal::new(0, 0);
+
+        for (tx_type, client, tx, amount) in txs {
+            let result = acc_man.process_tx(&Transaction::new(tx_type.clone(), client, tx, amount));
+            // A retried record changes nothing
+            if !matches!(result, Ok(TxOutcome::Applied)) {
+                continue;
+            }
+            let account = expected.entry(client).or_default();
+            let (delta_total, delta_held) = match tx_type {
+                TxType::Deposit => {
+                    applied.insert(tx, (TxType::Deposit, amount.unwrap()));
+                    (amount.unwrap(), Decimal::new(0, 0))
+                }
+                TxType::Withdraw => {
+                    applied.insert(tx, (TxType::Withdraw, amount.unwrap()));
+                    (-amount.unwrap(), Decimal::new(0, 0))
+                }
+                TxType::Dispute => match applied[&tx] {
+                    (TxType::Withdraw, a) => (a, a),
+                    (_, a) => (Decimal::new(0, 0), a),
+                },
+                TxType::Resolve => match applied[&tx] {
+                    (TxType::Withdraw, a) => (-a, -a),
+                    (_, a) => (Decimal::new(0, 0), -a),
+                },
+                TxType::Chargeback => match applied[&tx] {
+                    (TxType::Withdraw, a) => (Decimal::new(0, 0), -a),
+                    (_, a) => (-a, -a),
+                },
+            };
+            account.total += delta_total;
+            account.held += delta_held;
+            net_inflow += delta_total;
+
+            for acc in acc_man.accounts().unwrap() {
+                prop_assert_eq!(acc.total(), acc.available() + acc.held());
+                prop_assert!(acc.held() >= Decimal::new(0, 0));
+            }
+        }
+
+        let mut sum_of_totals = Decimal::new(0, 0);
+        for acc in acc_man.accounts().unwrap() {
+            let e = expected.get(&acc.client()).unwrap();
+            prop_assert_eq!(acc.total(), e.total);
+            prop_assert_eq!(acc.held(), e.held);
+            sum_of_totals += acc.total();
+            // The account is exactly the fold of its events
+            let events = acc_man.events_for_client(acc.client()).unwrap();
+            prop_assert_eq!(&ClientAccount::from_events(acc.client(), &events), &acc);
+        }
+        prop_assert_eq!(sum_of_totals, net_inflow);
+        prop_assert!(acc_man.trial_balance().unwrap().is_balanced());
+    }
+}
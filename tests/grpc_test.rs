@@ -0,0 +1,164 @@
+use bank_payments_system::account_manager::AccountManager;
+use bank_payments_system::grpc::proto::ledger_client::LedgerClient;
+use bank_payments_system::grpc::proto::ledger_server::LedgerServer;
+use bank_payments_system::grpc::proto::{
+    self, EventKind, GetAccountRequest, ListAccountsRequest, StreamEventsRequest, TxType,
+};
+use bank_payments_system::grpc::{LedgerService, ERROR_KIND_KEY, OUTCOME_KEY};
+use std::sync::{Arc, Mutex};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::StreamExt;
+use tonic::transport::{Channel, Server};
+use tonic::Code;
+
+/// Starts the service on a free port & connects a client to it.
+async fn start() -> LedgerClient<Channel> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acc_man = Arc::new(Mutex::new(AccountManager::default()));
+    tokio::spawn(
+        Server::builder()
+            .add_service(LedgerServer::new(LedgerService::new(acc_man)))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    LedgerClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap()
+}
+
+fn tx(tx_type: TxType, client: u32, tx: u32, amount: Option<&str>) -> proto::Transaction {
+    proto::Transaction {
+        r#type: tx_type.into(),
+        client,
+        tx,
+        amount: amount.map(str::to_string),
+        currency: proto::Currency::Unspecified.into(),
+        action: None,
+    }
+}
+
+#[tokio::test]
+async fn grpc_submits_transactions_and_reads_accounts() {
+    let mut client = start().await;
+    let account = client
+        .submit_transaction(tx(TxType::Deposit, 1, 1, Some("2.50005")))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(account.client, 1);
+    assert_eq!(account.balances[0].available, "2.5000");
+
+    client
+        .submit_transaction(tx(TxType::Deposit, 2, 2, Some("1.0")))
+        .await
+        .unwrap();
+    client
+        .submit_transaction(tx(TxType::Dispute, 1, 1, None))
+        .await
+        .unwrap();
+    let account = client
+        .get_account(GetAccountRequest { client: 1 })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(account.balances[0].held, "2.5000");
+
+    let accounts = client
+        .list_accounts(ListAccountsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .accounts;
+    let clients: Vec<u32> = accounts.iter().map(|a| a.client).collect();
+    assert_eq!(clients, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn grpc_reports_rejections_as_statuses() {
+    let mut client = start().await;
+    client
+        .submit_transaction(tx(TxType::Deposit, 1, 1, Some("1.0")))
+        .await
+        .unwrap();
+    let status = client
+        .submit_transaction(tx(TxType::Withdraw, 1, 2, Some("5.0")))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert_eq!(
+        status.metadata().get(ERROR_KIND_KEY).unwrap(),
+        "InsufficientFunds"
+    );
+
+    let replayed = client
+        .submit_transaction(tx(TxType::Deposit, 1, 1, Some("1.0")))
+        .await
+        .unwrap();
+    assert_eq!(replayed.metadata().get(OUTCOME_KEY).unwrap(), "replayed");
+    let status = client
+        .submit_transaction(tx(TxType::Deposit, 1, 1, Some("2.0")))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists);
+    let status = client
+        .submit_transaction(tx(TxType::Deposit, 1, 3, Some("one")))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = client
+        .get_account(GetAccountRequest { client: 9 })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn grpc_streams_history_then_follows_new_events() {
+    let mut client = start().await;
+    client
+        .submit_transaction(tx(TxType::Deposit, 1, 1, Some("3.0")))
+        .await
+        .unwrap();
+    client
+        .submit_transaction(tx(TxType::Deposit, 2, 2, Some("1.0")))
+        .await
+        .unwrap();
+
+    let history: Vec<proto::Event> = client
+        .stream_events(StreamEventsRequest {
+            client: None,
+            follow: false,
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(history.len(), 2);
+
+    let mut events = client
+        .stream_events(StreamEventsRequest {
+            client: Some(1),
+            follow: true,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let first = events.next().await.unwrap().unwrap();
+    assert_eq!(first.kind(), EventKind::Deposited);
+    assert_eq!(first.amount.as_deref(), Some("3.0"));
+
+    client
+        .submit_transaction(tx(TxType::Deposit, 2, 3, Some("1.0")))
+        .await
+        .unwrap();
+    client
+        .submit_transaction(tx(TxType::Dispute, 1, 1, None))
+        .await
+        .unwrap();
+    let held = events.next().await.unwrap().unwrap();
+    assert_eq!(held.kind(), EventKind::FundsHeld);
+    assert_eq!((held.client, held.tx), (1, 1));
+    assert_eq!(held.tx_type(), TxType::Deposit);
+}
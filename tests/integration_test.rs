@@ -1,6 +1,20 @@
-use bank_payments_system::account_manager::AccountManager;
+use bank_payments_system::account_manager::{AccountManager, DisputePolicy};
+use bank_payments_system::account_writer::CsvAccountWriter;
+use bank_payments_system::ledger_store::SledStore;
+use bank_payments_system::reject_sink::RejectSink;
+use bank_payments_system::snapshot::Snapshot;
 use bank_payments_system::tx_processor::TxProcessor;
-use bank_payments_system::tx_stream_reader::TxStreamReader;
+use bank_payments_system::tx_stream_merger::MergeStrategy;
+use bank_payments_system::tx_stream_reader::{InputFormat, TxStreamReader};
+use flate2::write::GzEncoder;
+use rust_decimal::Decimal;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A path under the temp dir unique to this test process, so concurrent runs don't collide.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bps_{}_{}", std::process::id(), name))
+}
 
 #[tokio::test]
 async fn payments_system_does_not_panic_against_csv() {
@@ -10,3 +24,328 @@ async fn payments_system_does_not_panic_against_csv() {
     tx_processor.start().await;
     tx_processor.print_accounts();
 }
+
+#[tokio::test]
+async fn payments_system_output_matches_out_csv() {
+    let tx_reader = TxStreamReader::new_from_csv("transactions.csv".into()).unwrap();
+    let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default());
+    tx_processor.start().await;
+
+    let mut out = Vec::new();
+    tx_processor
+        .write_accounts(&CsvAccountWriter, &mut out)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        std::fs::read_to_string("out.csv").unwrap()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn payments_system_sharded_output_matches_out_csv() {
+    let tx_reader = TxStreamReader::new_from_csv("transactions.csv".into()).unwrap();
+    let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default()).with_shards(4);
+    tx_processor.start().await;
+
+    let mut out = Vec::new();
+    tx_processor
+        .write_accounts(&CsvAccountWriter, &mut out)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        std::fs::read_to_string("out.csv").unwrap()
+    );
+}
+
+#[tokio::test]
+async fn payments_system_writes_rejected_rows_to_sink() {
+    let rejects_path = temp_path("integration_rejects.jsonl");
+    let tx_reader = TxStreamReader::new_from_csv("transactions.csv".into()).unwrap();
+    let acc_man = AccountManager::default();
+    let mut tx_processor = TxProcessor::new(tx_reader, acc_man)
+        .with_reject_sink(RejectSink::from_path(&rejects_path).unwrap());
+    tx_processor.start().await;
+
+    let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+    let first: serde_json::Value = serde_json::from_str(rejects.lines().next().unwrap()).unwrap();
+    assert_eq!(first["line"], 3);
+    assert_eq!(first["record"], "resolve, 1, 1");
+    assert_eq!(first["kind"], "InvalidDisputeTransition");
+    assert!(rejects
+        .lines()
+        .any(|l| l.contains("\"kind\":\"InsufficientFunds\"")));
+}
+
+#[tokio::test]
+async fn payments_system_rejects_keep_the_raw_record() {
+    let mut csv = b"type,client,tx,amount\n\"withdraw\", 1,  1, 1.0\ndeposit,1,2,".to_vec();
+    csv.extend_from_slice(b"\xff\n");
+    let rejects_path = temp_path("raw_rejects.jsonl");
+    let tx_reader = TxStreamReader::from_reader(std::io::Cursor::new(csv), InputFormat::Csv);
+    let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default())
+        .with_reject_sink(RejectSink::from_path(&rejects_path).unwrap());
+    tx_processor.start().await;
+
+    let rejects: Vec<serde_json::Value> = std::fs::read_to_string(&rejects_path)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(rejects[0]["record"], "\"withdraw\", 1,  1, 1.0");
+    assert_eq!(rejects[1]["kind"], "ReadError");
+    assert_eq!(rejects[1]["record"], "deposit,1,2,\u{fffd}");
+}
+
+#[tokio::test]
+async fn payments_system_reads_from_in_memory_buffer() {
+    let csv = "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdraw, 1, 2, 1.0\n";
+    let tx_reader = TxStreamReader::from_reader(
+        std::io::Cursor::new(csv.as_bytes().to_vec()),
+        InputFormat::Csv,
+    );
+    let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default());
+    tx_processor.start().await;
+
+    let account = tx_processor.account_manager().account(1).unwrap().unwrap();
+    assert_eq!(account.available(), Decimal::new(15, 1));
+    assert_eq!(account.total(), Decimal::new(15, 1));
+}
+
+#[tokio::test]
+async fn payments_system_reads_compressed_csv() {
+    let csv = std::fs::read("transactions.csv").unwrap();
+    let gz_path = temp_path("integration_transactions.csv.gz");
+    let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&csv).unwrap();
+    std::fs::write(&gz_path, gz.finish().unwrap()).unwrap();
+    // No extension, so the zstd stream is detected from its magic bytes
+    let zst_path = temp_path("integration_transactions_zst");
+    std::fs::write(&zst_path, zstd::encode_all(&csv[..], 0).unwrap()).unwrap();
+
+    for path in [gz_path, zst_path] {
+        let tx_reader = TxStreamReader::new_from_csv(path.to_str().unwrap().into()).unwrap();
+        let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default());
+        tx_processor.start().await;
+
+        let acc_man = tx_processor.account_manager();
+        assert_eq!(acc_man.accounts().unwrap().len(), 4);
+        let account = acc_man.account(3).unwrap().unwrap();
+        assert_eq!(account.total(), Decimal::new(215578, 4));
+    }
+}
+
+#[tokio::test]
+async fn payments_system_reads_jsonl() {
+    let jsonl = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 21.557788}
+{"type": "withdraw", "client": 1, "tx": 2, "amount": "1.5"}
+
+{"type": "dispute", "client": 1, "tx": 1}
+{"type": "doesnt_exist", "client": 1, "tx": 3}
+"#;
+    let rejects_path = temp_path("integration_jsonl_rejects.jsonl");
+    let tx_reader = TxStreamReader::from_reader(
+        std::io::Cursor::new(jsonl.as_bytes().to_vec()),
+        InputFormat::Jsonl,
+    );
+    let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default())
+        .with_reject_sink(RejectSink::from_path(&rejects_path).unwrap());
+    tx_processor.start().await;
+
+    let account = tx_processor.account_manager().account(1).unwrap().unwrap();
+    assert_eq!(account.available(), Decimal::new(-15, 1));
+    assert_eq!(account.held(), Decimal::new(215578, 4));
+
+    let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+    let reject: serde_json::Value = serde_json::from_str(rejects.trim()).unwrap();
+    assert_eq!(reject["line"], 5);
+    assert_eq!(reject["kind"], "ParseError");
+}
+
+#[tokio::test]
+async fn payments_system_merges_feeds_from_a_directory() {
+    let dir = temp_path("integration_feeds");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("a.csv"),
+        "type,client,tx,amount,timestamp\ndeposit,1,1,5.0,10\nwithdraw,1,2,2.0,30\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("b.jsonl"),
+        concat!(
+            r#"{"type": "deposit", "client": 2, "tx": 1, "amount": 1.0, "timestamp": 20}"#,
+            "\n",
+            r#"{"type": "deposit", "client": 2, "tx": 3, "amount": 3.0, "timestamp": 40}"#,
+            "\n",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0, "timestamp": 50}"#,
+            "\n",
+        ),
+    )
+    .unwrap();
+    let rejects_path = temp_path("integration_feed_rejects.jsonl");
+
+    let tx_readers = TxStreamReader::open_all(dir.to_str().unwrap(), None).unwrap();
+    assert_eq!(tx_readers.len(), 2);
+    let mut tx_processor = TxProcessor::from_sources(
+        tx_readers,
+        MergeStrategy::Timestamp,
+        AccountManager::default(),
+    )
+    .with_reject_sink(RejectSink::from_path(&rejects_path).unwrap());
+    tx_processor.start().await;
+
+    let acc_man = tx_processor.account_manager();
+    assert_eq!(
+        acc_man.account(1).unwrap().unwrap().total(),
+        Decimal::new(3, 0)
+    );
+    assert_eq!(
+        acc_man.account(2).unwrap().unwrap().total(),
+        Decimal::new(3, 0)
+    );
+
+    let rejects: Vec<serde_json::Value> = std::fs::read_to_string(&rejects_path)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(rejects.len(), 2);
+    assert_eq!(rejects[0]["kind"], "TransactionConflict");
+    assert_eq!(rejects[0]["line"], 1);
+    assert!(rejects[0]["source"].as_str().unwrap().ends_with("b.jsonl"));
+    // The deposit of a.csv repeated in b.jsonl changes nothing, but is still reported
+    assert_eq!(rejects[1]["kind"], "DuplicateAcrossSources");
+    assert_eq!(rejects[1]["line"], 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn payments_system_keeps_balances_across_runs_in_sled_store() {
+    let store_path = temp_path("integration_sled_store");
+    let _ = std::fs::remove_dir_all(&store_path);
+    let runs = [
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\n",
+        "type,client,tx,amount\ndeposit,1,3,2.0\ndispute,1,1,\n",
+    ];
+    // Each run starts from what the database holds, through a handle of its own. sled only
+    // lets one handle open a database at a time, so the handles share one open database.
+    let store = SledStore::open(&store_path).unwrap();
+    for csv in runs {
+        let tx_reader = TxStreamReader::from_reader(
+            std::io::Cursor::new(csv.as_bytes().to_vec()),
+            InputFormat::Csv,
+        );
+        let acc_man = AccountManager::new(Box::new(store.clone()), DisputePolicy::DepositsOnly);
+        let mut tx_processor = TxProcessor::new(tx_reader, acc_man).with_shards(2);
+        tx_processor.start().await;
+    }
+
+    let acc_man = AccountManager::new(Box::new(store), DisputePolicy::DepositsOnly);
+    let account = acc_man.account(1).unwrap().unwrap();
+    assert_eq!(account.total(), Decimal::new(7, 0));
+    assert_eq!(account.held(), Decimal::new(5, 0));
+    assert_eq!(acc_man.accounts().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn payments_system_resumes_from_write_ahead_log() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdraw,1,2,9.0\ndeposit,2,3,1.0\ndispute,1,1,\ndeposit,2,4,2.0\nresolve,1,1,\n";
+    let wal_path = temp_path("integration_resume.wal");
+    let _ = std::fs::remove_file(&wal_path);
+    let run = |csv: &str| {
+        let tx_reader = TxStreamReader::from_reader(
+            std::io::Cursor::new(csv.as_bytes().to_vec()),
+            InputFormat::Csv,
+        )
+        .with_name("feed.csv");
+        let acc_man = AccountManager::default().with_wal(&wal_path).unwrap();
+        TxProcessor::new(tx_reader, acc_man)
+    };
+
+    // The first run dies after the dispute on line 5
+    let interrupted: String = csv.lines().take(5).map(|l| format!("{}\n", l)).collect();
+    run(&interrupted).start().await;
+    let mut tx_processor = run(csv);
+    assert_eq!(
+        tx_processor
+            .account_manager()
+            .account(1)
+            .unwrap()
+            .unwrap()
+            .held(),
+        Decimal::new(5, 0)
+    );
+    tx_processor.start().await;
+
+    let acc_man = tx_processor.account_manager();
+    let account = acc_man.account(1).unwrap().unwrap();
+    assert_eq!(account.available(), Decimal::new(5, 0));
+    assert_eq!(account.held(), Decimal::new(0, 0));
+    assert_eq!(
+        acc_man.account(2).unwrap().unwrap().total(),
+        Decimal::new(3, 0)
+    );
+    // The withdrawal rejected for a lack of funds is logged too, so its retries stay rejected
+    let wal = std::fs::read_to_string(&wal_path).unwrap();
+    assert_eq!(wal.lines().count(), 6);
+    assert_eq!(
+        wal.lines()
+            .filter(|l| l.contains(r#""rejected":true"#))
+            .count(),
+        1
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn payments_system_restores_snapshot_and_replays_log_tail() {
+    let snapshot_path = temp_path("integration.snapshot");
+    let wal_path = temp_path("integration_snapshot.wal");
+    let _ = std::fs::remove_file(&snapshot_path);
+    let _ = std::fs::remove_file(&wal_path);
+    let reader = |csv: &str| {
+        TxStreamReader::from_reader(
+            std::io::Cursor::new(csv.as_bytes().to_vec()),
+            InputFormat::Csv,
+        )
+        .with_name("feed.csv")
+    };
+
+    // Sharded run taking a snapshot every 2 transactions & once at the end
+    let csv = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\ndispute,1,1,\ndeposit,2,3,2.0\ndeposit,3,4,4.0\n";
+    let acc_man = AccountManager::default().with_wal(&wal_path).unwrap();
+    let mut tx_processor = TxProcessor::new(reader(csv), acc_man)
+        .with_shards(2)
+        .with_snapshots(&snapshot_path, Some(2));
+    tx_processor.start().await;
+    let snapshot = Snapshot::read(&snapshot_path).unwrap();
+    assert_eq!(snapshot.wal_seq(), 5);
+    // The snapshot covers the whole log, so only the entry numbering carries on from is kept
+    assert_eq!(
+        std::fs::read_to_string(&wal_path).unwrap().lines().count(),
+        1
+    );
+
+    // A later run which only reaches the log
+    let more = format!("{}resolve,1,1,\n", csv);
+    let acc_man = AccountManager::from_snapshot(snapshot, DisputePolicy::DepositsOnly)
+        .unwrap()
+        .with_wal(&wal_path)
+        .unwrap();
+    TxProcessor::new(reader(&more), acc_man).start().await;
+
+    // Entries up to the snapshot are not replayed again, or the deposits would be duplicates
+    let acc_man = AccountManager::from_snapshot(
+        Snapshot::read(&snapshot_path).unwrap(),
+        DisputePolicy::DepositsOnly,
+    )
+    .unwrap()
+    .with_wal(&wal_path)
+    .unwrap();
+    let account = acc_man.account(1).unwrap().unwrap();
+    assert_eq!(account.available(), Decimal::new(5, 0));
+    assert_eq!(account.held(), Decimal::new(0, 0));
+    assert_eq!(
+        acc_man.account(3).unwrap().unwrap().total(),
+        Decimal::new(4, 0)
+    );
+}
@@ -0,0 +1,151 @@
+use bank_payments_system::account_manager::AccountManager;
+use bank_payments_system::account_writer::CsvAccountWriter;
+use bank_payments_system::reject_sink::RejectSink;
+use bank_payments_system::tx_processor::TxProcessor;
+use bank_payments_system::tx_stream_reader::TxStreamReader;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SCENARIOS_DIR: &str = "tests/scenarios";
+
+/// Rejections minus their timestamp, one JSON object per line.
+fn normalize_errors(jsonl: &str) -> Vec<String> {
+    jsonl
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let mut value: serde_json::Value = serde_json::from_str(l).unwrap();
+            if let Some(obj) = value.as_object_mut() {
+                obj.remove("timestamp");
+            }
+            value.to_string()
+        })
+        .collect()
+}
+
+/// An ordered line diff, so a line which is repeated, missing once or moved is reported too.
+fn diff(name: &str, expected: &[String], actual: &[String]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    // Longest common subsequence of the remaining lines, from the end
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut report = format!("--- {}\n", name);
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            report.push_str(&format!("  {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || common[i][j + 1] >= common[i + 1][j])
+        {
+            report.push_str(&format!("+ {}\n", actual[j]));
+            j += 1;
+        } else {
+            report.push_str(&format!("- {}\n", expected[i]));
+            i += 1;
+        }
+    }
+    Some(report)
+}
+
+fn lines(text: &str) -> Vec<String> {
+    text.lines().map(String::from).collect()
+}
+
+/// A sharded run must give the same output as a sequential one, so the expected files
+/// are only blessed from the sequential run.
+async fn run_scenario(dir: &Path, shards: usize) -> Vec<String> {
+    let name = dir.file_name().unwrap().to_string_lossy().to_string();
+    let rejects_path = std::env::temp_dir().join(format!(
+        "bps_scenario_{}_{}_{}.jsonl",
+        std::process::id(),
+        name,
+        shards
+    ));
+    let tx_reader = TxStreamReader::new_from_csv(dir.join("input.csv").to_string_lossy().into())
+        .unwrap()
+        .with_name("input.csv");
+    let mut tx_processor = TxProcessor::new(tx_reader, AccountManager::default())
+        .with_shards(shards)
+        .with_reject_sink(RejectSink::from_path(&rejects_path).unwrap());
+    tx_processor.start().await;
+
+    let mut out = Vec::new();
+    tx_processor
+        .write_accounts(&CsvAccountWriter, &mut out)
+        .unwrap();
+    let actual_accounts = String::from_utf8(out).unwrap();
+    let actual_errors = normalize_errors(&fs::read_to_string(&rejects_path).unwrap());
+
+    let expected_csv = dir.join("expected.csv");
+    let expected_errors = dir.join("expected_errors.jsonl");
+    if shards == 1 && std::env::var_os("BLESS").is_some() {
+        fs::write(&expected_csv, &actual_accounts).unwrap();
+        let mut errors = actual_errors.join("\n");
+        if !errors.is_empty() {
+            errors.push('\n');
+        }
+        fs::write(&expected_errors, errors).unwrap();
+        return Vec::new();
+    }
+
+    let mut failures = Vec::new();
+    let expected_accounts = fs::read_to_string(&expected_csv).unwrap_or_default();
+    if let Some(d) = diff(
+        &format!("{}/expected.csv", name),
+        &lines(&expected_accounts),
+        &lines(&actual_accounts),
+    ) {
+        failures.push(d);
+    }
+    let expected = normalize_errors(&fs::read_to_string(&expected_errors).unwrap_or_default());
+    if let Some(d) = diff(
+        &format!("{}/expected_errors.jsonl", name),
+        &expected,
+        &actual_errors,
+    ) {
+        failures.push(d);
+    }
+    failures
+}
+
+fn scenario_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(SCENARIOS_DIR)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.join("input.csv").is_file())
+        .collect();
+    dirs.sort();
+    assert!(!dirs.is_empty(), "No scenarios found in {}", SCENARIOS_DIR);
+    dirs
+}
+
+/// Runs every `tests/scenarios/<name>/input.csv` & compares the accounts & rejections
+/// with `expected.csv` & `expected_errors.jsonl`. Run with `BLESS=1` to regenerate them.
+#[tokio::test]
+async fn scenarios_match_expected_output() {
+    let mut failures = Vec::new();
+    for dir in &scenario_dirs() {
+        failures.extend(run_scenario(dir, 1).await);
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn scenarios_match_expected_output_when_sharded() {
+    let mut failures = Vec::new();
+    for dir in &scenario_dirs() {
+        failures.extend(run_scenario(dir, 4).await);
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}